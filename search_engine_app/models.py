@@ -3,4 +3,5 @@ from search_backend import db
 # Models
 class Site(db.Model):
     url = db.Column(db.String(100), primary_key=True)
-    title = db.Column(db.String(50))
\ No newline at end of file
+    title = db.Column(db.String(50))
+    body_text = db.Column(db.Text) # Visible page text, filled in by the crawler
\ No newline at end of file
@@ -1,5 +1,6 @@
 from search_backend import app, db
 from models import Site
+from sqlalchemy import or_
 import re
 
 # CREDIT: ChatGPT 4o 
@@ -63,10 +64,15 @@ def execute_search(raw_query):
 
 def search_term(term):
     term = term.lower().strip()
-    delimeter_pattern = r"[%/\.-]"
+    delimeter_pattern = r"[%/\.:?=&-]"
+    word_pattern = r"\w+"
     site_map = None
     with app.app_context():
-        site_map = Site.query.all()
+        # Narrow things down in SQL first, then check for whole words below
+        # Escaped so % and _ in a term are matched literally, same as the crawler's search command
+        escaped = term.replace("\\", "\\\\").replace("%", "\\%").replace("_", "\\_")
+        like = f"%{escaped}%"
+        site_map = Site.query.filter(or_(Site.title.ilike(like, escape="\\"), Site.url.ilike(like, escape="\\"), Site.body_text.ilike(like, escape="\\"))).all()
     urls = []
     title_map = {}
     for entry in site_map:
@@ -80,6 +86,10 @@ def search_term(term):
         if term in re.split(delimeter_pattern, entry.url):
             urls.append(entry.url)
             title_map[entry.url] = entry.title
+            continue
+        if entry.body_text and term in re.findall(word_pattern, entry.body_text.lower()):
+            urls.append(entry.url)
+            title_map[entry.url] = entry.title
     return (urls, title_map)
 
 # def dirty_url(url):
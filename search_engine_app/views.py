@@ -9,9 +9,9 @@ starting_url = "theuselessweb.com/"
 
 # Helpers
 def search_more(urls, num=100):
-    urls = list(map(lambda url: "https://"+url, urls))
+    urls = list(map(lambda url: url if "://" in url else "https://"+url, urls))
     #all_urls = ' '.join(urls)
-    command_list = ["./crawler", "-l", "0", "-n", f"{num}", "-d", "./search_db.db"]+urls
+    command_list = ["./crawler", "crawl", "-l", "0", "-n", f"{num}", "-d", "./search_db.db"]+urls
     print(command_list)
     subprocess.run(command_list)
 
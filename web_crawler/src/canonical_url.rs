@@ -0,0 +1,143 @@
+use std::fmt;
+use url::Url;
+
+///Query parameters stripped by default - tracking junk and session ids that don't change the page
+pub const DEFAULT_STRIPPED_PARAMS: &[&str] = &["utm_*", "fbclid", "gclid", "msclkid", "mc_eid", "sid", "sessionid", "session_id", "jsessionid", "phpsessid", "aspsessionid"];
+
+///Decides which query parameters are significant enough to keep in a canonical url
+#[derive(Clone, Debug)]
+pub struct QueryFilter {
+    keep: Vec<String>, //If non-empty, ONLY parameters matching one of these are kept
+    strip: Vec<String>
+}
+impl QueryFilter {
+    ///Patterns are matched case-insensitively against parameter names, and may end in `*` to match a prefix
+    pub fn new(keep: Vec<String>, strip: Vec<String>) -> QueryFilter {
+        let lower = |patterns: Vec<String>| patterns.into_iter().map(|pat| pat.to_lowercase()).collect();
+        QueryFilter { keep: lower(keep), strip: lower(strip) }
+    }
+    pub fn keeps(&self, param: &str) -> bool {
+        let param = param.to_lowercase();
+        let matches = |pat: &String| match pat.strip_suffix('*') {
+            Some(prefix) => param.starts_with(prefix),
+            None => param == *pat
+        };
+        (self.keep.is_empty() || self.keep.iter().any(matches)) && !self.strip.iter().any(matches)
+    }
+}
+impl Default for QueryFilter {
+    fn default() -> QueryFilter { QueryFilter::new(Vec::new(), DEFAULT_STRIPPED_PARAMS.iter().map(|pat| pat.to_string()).collect()) }
+}
+
+///A url in the one form we use to key pages: http(s) scheme kept, host lowercased, default port and
+///fragment dropped, and only significant query parameters left
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct CanonicalUrl(String);
+impl CanonicalUrl {
+    pub fn parse(raw: &str, filter: &QueryFilter) -> Option<CanonicalUrl> {
+        let mut url = Url::parse(raw.trim()).ok()?;
+        if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() { return None }
+        url.set_fragment(None);
+        url.set_username("").ok()?;
+        url.set_password(None).ok()?;
+        //Whole raw parameters are dropped or kept as they are - decoding and re-encoding them would change the url the server sees
+        if let Some(query) = url.query().map(String::from) {
+            let segments: Vec<&str> = query.split('&').collect();
+            let kept: Vec<&str> = segments.iter().copied().filter(|segment| !segment.is_empty() && filter.keeps(&param_name(segment))).collect();
+            if kept.is_empty() { url.set_query(None); }
+            else if kept.len() < segments.len() { url.set_query(Some(&kept.join("&"))); }
+        }
+        Some(CanonicalUrl(url.into()))
+    }
+    ///Rebuilds a url read back out of the database. Older databases stored urls without their
+    ///protocol (they were always fetched over https), so those get it put back.
+    pub fn from_stored(stored: &str) -> Option<CanonicalUrl> {
+        if stored.contains("://") { Some(CanonicalUrl(stored.to_string())) }
+        else { CanonicalUrl::parse(&format!("https://{stored}"), &QueryFilter::new(Vec::new(), Vec::new())) }
+    }
+    pub fn as_str(&self) -> &str { &self.0 }
+    fn after_scheme(&self) -> &str {
+        let start = self.0.find("://").map_or(0, |idx| idx + 3);
+        &self.0[start..]
+    }
+    ///Host, with the port if it isn't the default one
+    pub fn host(&self) -> &str {
+        let rest = self.after_scheme();
+        match rest.find(['/', '?']) { Some(idx) => &rest[..idx], None => rest }
+    }
+    ///Path along with the query, if there is one
+    pub fn path(&self) -> &str {
+        let rest = self.after_scheme();
+        match rest.find(['/', '?']) { Some(idx) => &rest[idx..], None => "/" }
+    }
+    ///Scheme and host (with port) - i.e. where robots.txt lives
+    pub fn origin(&self) -> &str {
+        let origin_len = self.0.len() - self.after_scheme().len() + self.host().len();
+        &self.0[..origin_len]
+    }
+}
+///The decoded name of a raw `name=value` query parameter
+fn param_name(segment: &str) -> String {
+    let raw_name = segment.split('=').next().unwrap_or_default();
+    url::form_urlencoded::parse(raw_name.as_bytes()).next().map_or_else(String::new, |(name, _)| name.into_owned())
+}
+
+impl fmt::Display for CanonicalUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(&self.0) }
+}
+
+///Shorthand for tests - a url written out as it'd be stored
+#[cfg(test)]
+pub fn url(raw: &str) -> CanonicalUrl { CanonicalUrl::from_stored(raw).unwrap() }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn canon(raw: &str) -> Option<String> { CanonicalUrl::parse(raw, &QueryFilter::default()).map(|url| url.to_string()) }
+    //CanonicalUrl Tests
+    #[test]
+    fn keeps_scheme_and_port() {
+        assert_eq!(canon("http://Gwango.LOL:8080/Page"), Some("http://gwango.lol:8080/Page".to_string()));
+        assert_eq!(canon("https://gwango.lol:443/"), Some("https://gwango.lol/".to_string()));
+        assert_eq!(canon("http://gwango.lol:80"), Some("http://gwango.lol/".to_string()));
+    }
+    #[test]
+    fn drops_fragment_and_junk_params() {
+        assert_eq!(canon("https://gwango.lol/list?page=2&utm_source=x&UTM_medium=y#top"), Some("https://gwango.lol/list?page=2".to_string()));
+        assert_eq!(canon("https://gwango.lol/?PHPSESSID=abc"), Some("https://gwango.lol/".to_string()));
+    }
+    #[test]
+    fn keep_list_wins() {
+        let filter = QueryFilter::new(vec!["page".to_string(), "q*".to_string()], vec!["query_debug".to_string()]);
+        let url = CanonicalUrl::parse("https://gwango.lol/s?page=2&sort=asc&query=hi&query_debug=1", &filter).unwrap();
+        assert_eq!(url.as_str(), "https://gwango.lol/s?page=2&query=hi");
+    }
+    #[test]
+    fn leaves_params_alone() {
+        //Params without values, and reserved characters, go through untouched whether or not anything is stripped
+        assert_eq!(canon("https://ex.com/?amp"), Some("https://ex.com/?amp".to_string()));
+        assert_eq!(canon("https://ex.com/index.php?title=Foo&action"), Some("https://ex.com/index.php?title=Foo&action".to_string()));
+        assert_eq!(canon("https://ex.com/?a=1;b=2"), Some("https://ex.com/?a=1;b=2".to_string()));
+        assert_eq!(canon("https://ex.com/?q=a+b%26c&utm_source=x&flag"), Some("https://ex.com/?q=a+b%26c&flag".to_string()));
+        assert_eq!(canon("https://ex.com/?utm%5Fsource=x&a=%2F"), Some("https://ex.com/?a=%2F".to_string()));
+        assert_eq!(canon("https://ex.com/?"), Some("https://ex.com/".to_string()));
+    }
+    #[test]
+    fn rejects_other_schemes() {
+        assert_eq!(canon("mailto:me@gwango.lol"), None);
+        assert_eq!(canon("ftp://gwango.lol/file"), None);
+        assert_eq!(canon("not a url"), None);
+    }
+    #[test]
+    fn url_parts() {
+        let url = CanonicalUrl::parse("http://gwango.lol:8080/a/b?c=d", &QueryFilter::default()).unwrap();
+        assert_eq!(url.host(), "gwango.lol:8080");
+        assert_eq!(url.path(), "/a/b?c=d");
+        assert_eq!(url.origin(), "http://gwango.lol:8080");
+    }
+    #[test]
+    fn from_stored_legacy() {
+        assert_eq!(CanonicalUrl::from_stored("gwango.lol/a").unwrap().as_str(), "https://gwango.lol/a");
+        assert_eq!(CanonicalUrl::from_stored("http://gwango.lol/a").unwrap().as_str(), "http://gwango.lol/a");
+    }
+}
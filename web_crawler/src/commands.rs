@@ -0,0 +1,251 @@
+use crate::prelude::*;
+use crate::crawler_datatypes::{ParsedPage, ParseOptions};
+use crate::crawler_utilities::parse_page;
+use crate::canonical_url::CanonicalUrl;
+use crate::database_interaction::{SqliteStore, upgrade_db};
+use crate::page_encoding::decode_page;
+use rusqlite::types::Value as SqlValue;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+///Pages reparsed per database transaction
+const REPARSE_BATCH: usize = 100;
+
+///Words too common to be worth searching on, same list as the search app's
+const COMMON_WORDS: &[&str] = &[
+    //Articles
+    "the", "a", "an",
+    //Conjunctions
+    "and", "but", "or", "nor", "so", "for", "yet", "although", "because", "since", "unless", "while", "whereas", "though", "after", "before", "once", "if", "as", "whether",
+    //Prepositions
+    "in", "on", "at", "by", "with", "about", "against", "between", "into", "through", "during", "above", "below", "to", "from", "up", "down", "over", "under", "of",
+    "off", "out", "around", "near", "along", "throughout", "until", "within", "without",
+    //Pronouns
+    "he", "she", "it", "we", "they", "you", "I", "me", "him", "her", "us", "them", "my", "your", "his", "its", "our", "their", "mine", "yours", "hers", "ours", "theirs",
+    "this", "that", "these", "those", "who", "whom", "whose", "which", "what", "where", "when", "why", "how", "anyone", "someone", "everyone", "no one", "none", "nothing",
+    //Other function words
+    "is", "are", "was", "were", "be", "been", "being", "am", "do", "does", "did", "can", "could", "will", "would", "shall", "should", "may", "might", "must", "have", "has",
+    "had", "not", "no", "yes", "all", "any", "some", "few", "more", "most", "much", "many", "each", "every", "either", "neither", "both", "only", "just", "even", "also",
+    "always", "never", "again", "perhaps"
+];
+
+///What the db command can do
+#[derive(clap::Subcommand, Debug)]
+pub enum DbAction {
+    /// Create a new database with the current schema
+    Init,
+    /// Bring an existing database (including one the web app made) up to the current schema
+    Migrate
+}
+
+///Tables that can be exported
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ExportTable {
+    Site,
+    Link,
+    Alias,
+    Failed,
+    Frontier
+}
+impl ExportTable {
+    fn as_str(&self) -> &'static str {
+        match self { ExportTable::Site => "site", ExportTable::Link => "link", ExportTable::Alias => "alias", ExportTable::Failed => "failed", ExportTable::Frontier => "frontier" }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ExportFormat {
+    Jsonl,
+    Csv
+}
+
+///Reports what went wrong and quits with a non-zero exit code, so scripts can tell the command didn't work
+pub fn fail(msg: impl std::fmt::Display) -> ! {
+    eprintln!("{msg}");
+    std::process::exit(1)
+}
+
+pub fn run_db(action: DbAction, db_path: &PathBuf, disp: impl Fn(String, u8)) {
+    match action {
+        DbAction::Init => {
+            if db_path.exists() { fail(format!("There's already something at {} - use `db migrate` to bring a database up to date", db_path.display())) }
+            match SqliteStore::open(db_path).and_then(|store| store.stats(0)) {
+                Ok(stats) => disp(format!("Created {} at schema version {}", db_path.display(), stats.version), 1),
+                Err(e) => fail(format!("DATABASE ERROR: {e}\nTLDR; Couldn't create the database - the path is probably wrong"))
+            }
+        },
+        DbAction::Migrate => match upgrade_db(db_path) {
+            Ok((before, after)) if before == after => disp(format!("{} is already at schema version {after}", db_path.display()), 1),
+            Ok((before, after)) => disp(format!("Migrated {} from schema version {before} to {after}", db_path.display()), 1),
+            Err(e) => fail(format!("DATABASE ERROR: {e}\nTLDR; Couldn't migrate the database - it's probably not one this crawler (or the web app) made"))
+        }
+    }
+}
+
+pub fn run_stats(db_path: &PathBuf, top: usize) {
+    let stats = match SqliteStore::open_read_only(db_path).and_then(|store| store.stats(top)) {
+        Ok(stats) => stats,
+        Err(e) => fail(format!("DATABASE ERROR: {e}\nTLDR; Couldn't read the database - the path is probably wrong, it needs a `db migrate`, or it's not a database this crawler made"))
+    };
+    println!("Schema version: {}\nPages: {} across {} hosts\nLinks: {}\nAliases: {}\nFrontier: {}\nKept raw pages: {}", stats.version, stats.pages, stats.host_count,
+        stats.links, stats.aliases, stats.frontier, stats.raw_pages);
+    if let Some(last_crawled) = stats.last_crawled {
+        println!("Last crawled: {}", httpdate::fmt_http_date(std::time::UNIX_EPOCH + Duration::from_secs(last_crawled.max(0) as u64)));
+    }
+    println!("By status:");
+    for (status, pages) in &stats.statuses { println!("  {}: {pages}", status.map_or("unknown".to_string(), |status| status.to_string())); }
+    println!("Top hosts:");
+    for (host, pages) in &stats.hosts { println!("  {host}: {pages}"); }
+    println!("Failed: {}", stats.failures.iter().map(|(_, pages)| pages).sum::<usize>());
+    for (kind, pages) in &stats.failures { println!("  {kind}: {pages}"); }
+}
+
+///Writes a table out as JSON Lines or CSV, to a file or stdout
+pub fn run_export(db_path: &PathBuf, table: ExportTable, format: ExportFormat, output: Option<&PathBuf>, disp: impl Fn(String, u8)) {
+    let store = match SqliteStore::open_read_only(db_path) {
+        Ok(store) => store,
+        Err(e) => fail(format!("DATABASE ERROR: {e}\nTLDR; Couldn't open the database - the path is probably wrong, it needs a `db migrate`, or it's not a database this crawler made"))
+    };
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(e) => fail(format!("EXPORT ERROR: {e}\nTLDR; Couldn't create {}", path.display()))
+        },
+        None => Box::new(BufWriter::new(io::stdout().lock()))
+    };
+    let mut rows = 0;
+    let dumped = store.dump_table(table.as_str(), |columns, values| {
+        match format {
+            ExportFormat::Jsonl => {
+                let line: serde_json::Map<String, Value> = columns.iter().cloned().zip(values.into_iter().map(json_value)).collect();
+                serde_json::to_writer(&mut out, &line)?;
+            },
+            ExportFormat::Csv => {
+                if rows == 0 { writeln!(out, "{}", columns.iter().map(|column| csv_field(column)).collect::<Vec<String>>().join(","))?; }
+                write!(out, "{}", values.into_iter().map(|value| csv_field(&sql_text(value))).collect::<Vec<String>>().join(","))?;
+            }
+        }
+        writeln!(out)?;
+        rows += 1;
+        Ok(())
+    }).and_then(|_| Ok(out.flush()?));
+    match dumped {
+        Ok(()) => disp(format!("Exported {rows} rows from {}", table.as_str()), if output.is_some() { 1 } else { 2 }),
+        Err(e) => fail(format!("EXPORT ERROR: {e}\nTLDR; Stopped after {rows} rows"))
+    }
+}
+
+///Searches crawled pages the way the search app does: a page scores a point for every term found as a whole word
+///in its title, url or text, and common words are left out (unless that's all there is)
+pub fn run_search(db_path: &PathBuf, query: &[String], limit: usize) {
+    let store = match SqliteStore::open_read_only(db_path) {
+        Ok(store) => store,
+        Err(e) => fail(format!("DATABASE ERROR: {e}\nTLDR; Couldn't open the database - the path is probably wrong, it needs a `db migrate`, or it's not a database this crawler made"))
+    };
+    let word_match = Regex::new(r"\w+").unwrap();
+    let mut scores: HashMap<String, (usize, Option<String>)> = HashMap::new();
+    for term in clean_terms(query) {
+        let candidates = match store.search_candidates(&term) {
+            Ok(candidates) => candidates,
+            Err(e) => fail(format!("DATABASE ERROR: {e}\nTLDR; Couldn't search the database"))
+        };
+        for (url, title, body_text) in candidates {
+            if matches_term(&word_match, &term, &url, title.as_deref(), body_text.as_deref()) { scores.entry(url).or_insert((0, title)).0 += 1; }
+        }
+    }
+    let mut results: Vec<(String, (usize, Option<String>))> = scores.into_iter().collect();
+    results.sort_by(|(url_a, (score_a, _)), (url_b, (score_b, _))| score_b.cmp(score_a).then_with(|| url_a.cmp(url_b)));
+    if results.is_empty() { return println!("No results :(") }
+    for (url, (_, title)) in results.into_iter().take(limit) { println!("{}\n  {url}", title.as_deref().unwrap_or("(untitled)")); }
+}
+
+///Runs the parser over every page with its body kept in the database, updating titles, text and links as it goes
+pub fn run_reparse(db_path: &PathBuf, parse_opts: &ParseOptions, disp: impl Fn(String, u8)) {
+    let mut store = match SqliteStore::open_current(db_path) {
+        Ok(store) => store,
+        Err(e) => fail(format!("DATABASE ERROR: {e}\nTLDR; Couldn't open the database - the path is probably wrong, it needs a `db migrate`, or it's not a database this crawler made"))
+    };
+    let (mut after, mut reparsed) = (0, 0);
+    loop {
+        let pages = match store.raw_pages(after, REPARSE_BATCH) {
+            Ok((pages, last)) if last > after => { after = last; pages },
+            Ok(_) => break,
+            Err(e) => fail(format!("DATABASE ERROR: {e}\nTLDR; Couldn't read the kept pages back out of the database"))
+        };
+        let parsed: Vec<(CanonicalUrl, ParsedPage)> = pages.into_iter().map(|raw| {
+            let page = decode_page(&raw.body, raw.content_type.as_deref(), &raw.final_url);
+            disp(format!("REPARSING: {}", raw.url), 3);
+            (raw.url, parse_page(page, &raw.final_url, parse_opts))
+        }).collect();
+        if let Err(e) = store.update_parsed(&parsed) { fail(format!("DATABASE ERROR: {e}\nTLDR; Couldn't save the reparsed pages")) }
+        reparsed += parsed.len();
+    }
+    disp(format!("Reparsed {reparsed} pages"), 1);
+}
+
+///Lowercased search terms, minus the common words (unless there's nothing else)
+fn clean_terms(query: &[String]) -> Vec<String> {
+    let terms: Vec<String> = query.iter().flat_map(|part| part.split_whitespace()).map(str::to_lowercase).collect();
+    let clean: Vec<String> = terms.iter().filter(|term| !COMMON_WORDS.contains(&term.as_str())).cloned().collect();
+    if clean.is_empty() { terms } else { clean }
+}
+
+///Whether a (lowercase) term shows up as a whole word in a page's title, url or text. `word_match` picks the words out of the text.
+fn matches_term(word_match: &Regex, term: &str, url: &str, title: Option<&str>, body_text: Option<&str>) -> bool {
+    title.is_some_and(|title| title.to_lowercase().split(' ').any(|word| word == term))
+        || url.split(['%', '/', '.', ':', '?', '=', '&', '-']).any(|part| part == term)
+        || body_text.is_some_and(|text| word_match.find_iter(&text.to_lowercase()).any(|word| word.as_str() == term))
+}
+
+fn json_value(value: SqlValue) -> Value {
+    match value {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(num) => Value::from(num),
+        SqlValue::Real(num) => Value::from(num),
+        SqlValue::Text(text) => Value::from(text),
+        SqlValue::Blob(bytes) => Value::from(bytes.iter().map(|byte| format!("{byte:02x}")).collect::<String>())
+    }
+}
+
+fn sql_text(value: SqlValue) -> String {
+    match json_value(value) {
+        Value::Null => String::new(),
+        Value::String(text) => text,
+        other => other.to_string()
+    }
+}
+
+///Quotes a CSV field if it needs it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) { format!("\"{}\"", field.replace('"', "\"\"")) } else { field.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    //Search Tests
+    #[test]
+    fn clean_terms_drop_common_words() {
+        let query = vec!["The Crabs".to_string(), "of  rust".to_string()];
+        assert_eq!(clean_terms(&query), vec!["crabs", "rust"]);
+        assert_eq!(clean_terms(&["to be".to_string()]), vec!["to", "be"]);
+    }
+    #[test]
+    fn whole_word_matches() {
+        let word_match = Regex::new(r"\w+").unwrap();
+        assert!(matches_term(&word_match, "crabs", "https://gwango.lol/", Some("All About Crabs"), None));
+        assert!(matches_term(&word_match, "gwango", "https://gwango.lol/", None, None));
+        assert!(matches_term(&word_match, "filler", "https://gwango.lol/", None, Some("100% crab, no FILLER.")));
+        assert!(!matches_term(&word_match, "crab", "https://gwango.lol/crabs", Some("Crabs"), Some("crabs crabby")));
+    }
+    //Export Tests
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a, b"), "\"a, b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(sql_text(SqlValue::Null), "");
+        assert_eq!(sql_text(SqlValue::Integer(200)), "200");
+    }
+}
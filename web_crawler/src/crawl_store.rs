@@ -0,0 +1,191 @@
+use crate::prelude::*;
+use crate::crawler_datatypes::{PageData, FrontierEntry, FoundLink, ErrorKind};
+use crate::canonical_url::CanonicalUrl;
+use crate::database_interaction::SqliteStore;
+use crate::jsonl_store::JsonLinesStore;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+
+///A crawled page on its way to storage, along with the links found on it
+pub type PageRecord = (CanonicalUrl, PageData, Vec<FoundLink>);
+///A page we gave up on, what went wrong, and how many times we tried it
+pub type FailureRecord = (CanonicalUrl, ErrorKind, u32);
+
+///Somewhere crawl results are kept between runs
+pub trait CrawlStore: Send {
+    ///Urls earlier crawls already took care of - pages, urls that led to them, and pages that failed within the last `cooldown`
+    fn load_seen(&mut self, cooldown: Duration) -> Result<Vec<CanonicalUrl>>;
+    ///The links the last crawl didn't get to
+    fn load_frontier(&mut self) -> Result<Vec<FrontierEntry>>;
+    ///Saves a batch of pages and the links found on them. A page that can't be saved is noted in stats rather than sinking the batch.
+    fn write_pages(&mut self, pages: &[PageRecord], stats: &mut WriteStats);
+    fn write_failures(&mut self, failures: &[FailureRecord]) -> Result<()>;
+    ///Replaces the saved frontier
+    fn save_frontier(&mut self, entries: &[FrontierEntry]) -> Result<()>;
+}
+
+///Opens the store at a path - JSON Lines if it ends in .jsonl, otherwise a SQLite database
+pub fn open_store(path: &PathBuf) -> Result<Box<dyn CrawlStore>> {
+    if is_jsonl(path) { Ok(Box::new(JsonLinesStore::open(path)?)) } else { Ok(Box::new(SqliteStore::open(path)?)) }
+}
+
+pub fn is_jsonl(path: &std::path::Path) -> bool { path.extension().is_some_and(|ext| ext == "jsonl") }
+
+///Things the crawlers send the database writer
+pub enum DbMsg {
+    Page(CanonicalUrl, Box<PageData>, Vec<FoundLink>),
+    Failed(CanonicalUrl, ErrorKind, u32), //Along with how many times we tried it
+    Frontier(Vec<FrontierEntry>),
+    Stop
+}
+
+///Handle to the thread that streams crawl results into the store as they come in
+pub struct DbWriter {
+    sender: Sender<DbMsg>,
+    handle: thread::JoinHandle<WriteStats>
+}
+
+///What the database writer managed to do
+#[derive(Default, Debug)]
+pub struct WriteStats {
+    pub written: usize,
+    pub failed: Vec<(CanonicalUrl, String)> //Pages that couldn't be written, and why
+}
+impl DbWriter {
+    ///Starts the writer thread, which writes to the store every `batch_size` pages or every `batch_time`, whichever comes first
+    pub fn spawn(store: Box<dyn CrawlStore>, batch_size: usize, batch_time: Duration) -> DbWriter {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || write_loop(store, receiver, cmp::max(batch_size, 1), batch_time));
+        DbWriter { sender, handle }
+    }
+    pub fn sender(&self) -> Sender<DbMsg> { self.sender.clone() }
+    ///Replaces the saved frontier with the links this crawl didn't get to
+    pub fn save_frontier(&self, entries: Vec<FrontierEntry>) {
+        let _ = self.sender.send(DbMsg::Frontier(entries));
+    }
+    ///Flushes whatever is left, stops the writer thread and returns how the writing went overall
+    pub fn finish(self) -> WriteStats {
+        let _ = self.sender.send(DbMsg::Stop);
+        self.handle.join().expect("Database writer panicked :(")
+    }
+}
+
+fn write_loop(mut store: Box<dyn CrawlStore>, receiver: Receiver<DbMsg>, batch_size: usize, batch_time: Duration) -> WriteStats {
+    let mut batch = Vec::new();
+    let mut failures = Vec::new();
+    let mut stats = WriteStats::default();
+    let mut deadline = Instant::now() + batch_time;
+    loop {
+        let stop = match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(DbMsg::Page(url, data, links)) => { batch.push((url, *data, links)); false },
+            Ok(DbMsg::Failed(url, kind, attempts)) => { failures.push((url, kind, attempts)); false },
+            Ok(DbMsg::Frontier(entries)) => {
+                if let Err(e) = store.save_frontier(&entries) { eprintln!("DATABASE ERROR, couldn't save frontier: {e}"); }
+                false
+            },
+            Ok(DbMsg::Stop) | Err(RecvTimeoutError::Disconnected) => true,
+            Err(RecvTimeoutError::Timeout) => false
+        };
+        if stop || batch.len() >= batch_size || Instant::now() >= deadline {
+            if !batch.is_empty() { store.write_pages(&batch, &mut stats); }
+            if !failures.is_empty() {
+                if let Err(e) = store.write_failures(&failures) { eprintln!("DATABASE ERROR, couldn't save failed pages: {e}"); }
+            }
+            batch.clear();
+            failures.clear();
+            deadline = Instant::now() + batch_time;
+        }
+        if stop { return stats }
+    }
+}
+
+///Seconds since the unix epoch
+pub fn unix_now() -> i64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |since| since.as_secs() as i64)
+}
+
+///Keeps everything in memory, for tests
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    pub state: Arc<Mutex<MemoryState>>
+}
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryState {
+    pub pages: Vec<PageRecord>,
+    pub batches: Vec<usize>, //How many pages came in each write
+    pub failures: Vec<FailureRecord>,
+    pub frontier: Vec<FrontierEntry>
+}
+#[cfg(test)]
+impl CrawlStore for MemoryStore {
+    fn load_seen(&mut self, _cooldown: Duration) -> Result<Vec<CanonicalUrl>> {
+        let state = self.state.lock();
+        Ok(state.pages.iter().map(|(url, _, _)| url.clone()).chain(state.failures.iter().map(|(url, _, _)| url.clone())).collect())
+    }
+    fn load_frontier(&mut self) -> Result<Vec<FrontierEntry>> { Ok(self.state.lock().frontier.clone()) }
+    fn write_pages(&mut self, pages: &[PageRecord], stats: &mut WriteStats) {
+        let mut state = self.state.lock();
+        state.pages.extend_from_slice(pages);
+        state.batches.push(pages.len());
+        stats.written += pages.len();
+    }
+    fn write_failures(&mut self, failures: &[FailureRecord]) -> Result<()> {
+        self.state.lock().failures.extend_from_slice(failures);
+        Ok(())
+    }
+    fn save_frontier(&mut self, entries: &[FrontierEntry]) -> Result<()> {
+        self.state.lock().frontier = entries.to_vec();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonical_url::url;
+    //DbWriter Tests
+    #[test]
+    fn writer_batches() {
+        let store = MemoryStore::default();
+        let writer = DbWriter::spawn(Box::new(store.clone()), 2, Duration::from_secs(3600));
+        let sender = writer.sender();
+        for page in ["https://gwango.lol/a", "https://gwango.lol/b", "https://gwango.lol/c"] {
+            sender.send(DbMsg::Page(url(page), Box::default(), Vec::new())).unwrap();
+        }
+        sender.send(DbMsg::Failed(url("https://gwango.lol/gone"), ErrorKind::Status(404), 1)).unwrap();
+        //Two pages make a batch, so those get written without waiting for the timer
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while store.state.lock().pages.len() < 2 && Instant::now() < deadline { thread::sleep(Duration::from_millis(10)); }
+        assert_eq!(store.state.lock().pages.len(), 2);
+        writer.save_frontier(vec![FrontierEntry::start(url("https://gwango.lol/d"))]);
+        let stats = writer.finish();
+        assert_eq!(stats.written, 3);
+        let state = store.state.lock();
+        assert_eq!(state.batches, vec![2, 1]); //The last page is flushed on finish
+        assert_eq!(state.failures.len(), 1);
+        assert_eq!(state.frontier, vec![FrontierEntry::start(url("https://gwango.lol/d"))]);
+    }
+    #[test]
+    fn writer_batches_by_time() {
+        let store = MemoryStore::default();
+        let writer = DbWriter::spawn(Box::new(store.clone()), 100, Duration::from_millis(50));
+        writer.sender().send(DbMsg::Page(url("https://gwango.lol/a"), Box::default(), Vec::new())).unwrap();
+        //Nowhere near a full batch, but the timer runs out and it gets written anyway
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while store.state.lock().pages.is_empty() && Instant::now() < deadline { thread::sleep(Duration::from_millis(10)); }
+        assert_eq!(store.state.lock().batches, vec![1]);
+        writer.sender().send(DbMsg::Page(url("https://gwango.lol/b"), Box::default(), Vec::new())).unwrap();
+        let stats = writer.finish();
+        assert_eq!(stats.written, 2);
+        assert_eq!(store.state.lock().batches, vec![1, 1]);
+    }
+    #[test]
+    fn memory_store_seen() {
+        let mut store = MemoryStore::default();
+        let mut stats = WriteStats::default();
+        store.write_pages(&[(url("https://gwango.lol/"), PageData::default(), Vec::new())], &mut stats);
+        store.write_failures(&[(url("https://gwango.lol/gone"), ErrorKind::Timeout, 3)]).unwrap();
+        assert_eq!(store.load_seen(Duration::ZERO).unwrap(), vec![url("https://gwango.lol/"), url("https://gwango.lol/gone")]);
+    }
+}
@@ -1,105 +1,290 @@
 use crate::prelude::*;
-use rand::{Rng, rngs::ThreadRng};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use tokio::sync::Notify;
+use crate::canonical_url::{CanonicalUrl, QueryFilter};
+use crate::robots::RobotsDirectives;
+use crate::crawl_store::DbMsg;
+use std::sync::mpsc::Sender;
 
 const SKIP_AMOUNT: usize = 5;
 const SHAKE_THRESH: u8 = 10;
 
 pub struct SiteMap {
-    map: RwLock<HashMap<String, PageData>>,
+    map: RwLock<HashSet<CanonicalUrl>>, //Just the keys - the page data itself is streamed off to the database writer
     len: RwLock<usize>,
     capacity: usize,
-    bad_pages: RwLock<HashSet<String>>,
-    previously_searched: RwLock<HashSet<String>>,
-    pbar: Arc<ProgressBar>
+    bad_pages: RwLock<HashMap<CanonicalUrl, FailReason>>,
+    previously_searched: RwLock<HashSet<CanonicalUrl>>,
+    aliases: RwLock<HashSet<CanonicalUrl>>, //Urls that redirected to (or named a canonical url for) a page we've got under another key
+    pbar: Arc<ProgressBar>,
+    writer: Option<Sender<DbMsg>>
 }
 impl SiteMap {
-    pub fn new(capacity: u32, pbar: Arc<ProgressBar>) -> SiteMap {
-        SiteMap { map: RwLock::new(HashMap::<String, PageData>::new()), len: RwLock::new(0), capacity: capacity as usize, bad_pages: RwLock::new(HashSet::<String>::new()), previously_searched: RwLock::new(HashSet::new()), pbar }
+    pub fn new(capacity: u32, pbar: Arc<ProgressBar>, writer: Option<Sender<DbMsg>>) -> SiteMap {
+        SiteMap { map: RwLock::new(HashSet::<CanonicalUrl>::new()), len: RwLock::new(0), capacity: capacity as usize, bad_pages: RwLock::new(HashMap::<CanonicalUrl, FailReason>::new()), previously_searched: RwLock::new(HashSet::new()), aliases: RwLock::new(HashSet::new()), pbar, writer }
     }
-    pub fn insert(&self, key: String, data: PageData) -> bool {
+    pub fn insert(&self, key: CanonicalUrl, data: PageData, links: Vec<FoundLink>) -> bool {
         let mut len = self.len.write();
         if *len == self.capacity { return true; } //Can't insert, we done
         //println!("Inserting: {}", key);
         let mut map = self.map.write();
-        match map.insert(key, data) {
-            Some(_) => false, //Value was already in the map, do nothing
-            None => {
-                //Increment length and progress bar
-                self.pbar.inc(1);
-                *len += 1;
-                //If at capacity, we're done, so return true
-                *len == self.capacity
-            }
-        }
+        if map.contains(&key) { return false } //Value was already in the map, do nothing
+        map.insert(key.clone());
+        //Ship the data off to be saved, then increment length and progress bar
+        if let Some(writer) = &self.writer { let _ = writer.send(DbMsg::Page(key, Box::new(data), links)); }
+        self.pbar.inc(1);
+        *len += 1;
+        //If at capacity, we're done, so return true
+        *len == self.capacity
     }
-    pub fn insert_bad(&self, bad_url: String) {
+    pub fn insert_bad(&self, bad_url: CanonicalUrl, reason: FailReason) {
         let mut bad_pages = self.bad_pages.write();
-        bad_pages.insert(bad_url);
+        bad_pages.insert(bad_url, reason);
+    }
+    ///Marks a url as failed, and sends it off to be remembered for next time
+    pub fn insert_failed(&self, bad_url: CanonicalUrl, kind: ErrorKind, attempts: u32) {
+        if let Some(writer) = &self.writer { let _ = writer.send(DbMsg::Failed(bad_url.clone(), kind, attempts)); }
+        self.insert_bad(bad_url, FailReason::CrawlError(kind));
     }
-    pub fn insert_previously(&self, prev_url: String) {
+    pub fn bad_count(&self, reason: FailReason) -> usize { self.bad_count_where(|bad_reason| bad_reason == reason) }
+    pub fn bad_count_where(&self, pred: impl Fn(FailReason) -> bool) -> usize {
+        let bad_pages = self.bad_pages.read();
+        bad_pages.values().filter(|&&bad_reason| pred(bad_reason)).count()
+    }
+    pub fn insert_previously(&self, prev_url: CanonicalUrl) {
         let mut previously_searched = self.previously_searched.write();
         previously_searched.insert(prev_url);
     }
-    pub fn contains_key(&self, key: &String) -> bool { 
+    pub fn insert_aliases(&self, alias_urls: impl IntoIterator<Item = CanonicalUrl>) {
+        let mut aliases = self.aliases.write();
+        aliases.extend(alias_urls);
+    }
+    pub fn contains_key(&self, key: &CanonicalUrl) -> bool { 
         let map = self.map.read();
         let bad_pages = self.bad_pages.read();
         let previously_searched = self.previously_searched.read();
-        map.contains_key(key) || bad_pages.contains(key) || previously_searched.contains(key)
+        let aliases = self.aliases.read();
+        map.contains(key) || bad_pages.contains_key(key) || previously_searched.contains(key) || aliases.contains(key)
     }
     pub fn len(&self) -> usize { 
         let len = self.len.read();
         *len
     }
-    pub fn get_map(&self) -> RwLockReadGuard<HashMap<String, PageData>> {
-        self.map.read()
+}
+
+
+///Why a page ended up in the bad pages
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FailReason {
+    CrawlError(ErrorKind),
+    RobotsDisallowed,
+    NoIndex //Not a failure as such, but we crawled it and won't keep it
+}
+
+///What went wrong fetching a page
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorKind {
+    Timeout,
+    Connect, //Couldn't reach the host at all (DNS, refused, TLS...)
+    Body, //Dropped partway through the body
+    Status(u16),
+    Redirect, //Loops or too many hops
+    Other
+}
+impl ErrorKind {
+    ///Works out what sort of error a failed crawl hit
+    pub fn classify(error: &Error) -> ErrorKind {
+        if let Some(fetch_error) = error.downcast_ref::<FetchError>() { return fetch_error.kind }
+        match error.downcast_ref::<reqwest::Error>() {
+            Some(e) if e.is_timeout() => ErrorKind::Timeout,
+            Some(e) if e.is_connect() => ErrorKind::Connect,
+            Some(e) if e.is_body() || e.is_decode() => ErrorKind::Body,
+            Some(e) if e.is_redirect() => ErrorKind::Redirect,
+            _ => ErrorKind::Other
+        }
+    }
+    ///Whether trying again later might go better
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ErrorKind::Timeout | ErrorKind::Connect | ErrorKind::Body => true,
+            ErrorKind::Status(code) => matches!(code, 408 | 425 | 429 | 500 | 502 | 503 | 504),
+            ErrorKind::Redirect | ErrorKind::Other => false
+        }
+    }
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Connect => "connect",
+            ErrorKind::Body => "body",
+            ErrorKind::Status(_) => "status",
+            ErrorKind::Redirect => "redirect",
+            ErrorKind::Other => "other"
+        }
+    }
+}
+
+///An error we spotted ourselves while fetching a page, as opposed to one reqwest handed us
+#[derive(Debug)]
+pub struct FetchError {
+    pub kind: ErrorKind,
+    pub retry_after: Option<Duration>, //From a Retry-After header
+    pub msg: String
+}
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str(&self.msg) }
+}
+impl std::error::Error for FetchError {}
+
+///A redirect hop we weren't let through, which settles what happens to the url that led there
+#[derive(Debug)]
+pub enum Bounced {
+    RobotsDisallowed(CanonicalUrl),
+    HeldOff(CanonicalUrl, Instant) //The hop's host won't have us until then
+}
+impl std::fmt::Display for Bounced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Bounced::RobotsDisallowed(url) => write!(f, "Redirected to {url}, which robots.txt disallows"),
+            Bounced::HeldOff(url, _) => write!(f, "Redirected to {url}, whose host is holding us off")
+        }
     }
 }
+impl std::error::Error for Bounced {}
 
+///A url waiting to be crawled, along with how we found it
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FrontierEntry {
+    pub url: CanonicalUrl,
+    pub depth: u32, //Links followed from a starting point to get here
+    pub referrer: Option<CanonicalUrl>,
+    pub attempts: u32 //Times we've already tried and failed to fetch it this run
+}
+impl FrontierEntry {
+    pub fn start(url: CanonicalUrl) -> FrontierEntry { FrontierEntry { url, depth: 0, referrer: None, attempts: 0 } }
+    ///An entry for a link found on this entry's page
+    pub fn child(&self, url: CanonicalUrl) -> FrontierEntry {
+        FrontierEntry { url, depth: self.depth + 1, referrer: Some(self.url.clone()), attempts: 0 }
+    }
+}
 
 pub struct LinkList {
-    links: parking_lot::RwLock<VecDeque<String>>,
-    alert: RwCondvar,
-    capacity: u8
+    links: RwLock<VecDeque<FrontierEntry>>,
+    alert: Notify,
+    capacity: usize,
+    //The following are only changed while holding the links lock
+    live: AtomicUsize, //Workers that haven't gone home (or panicked) yet
+    idle: AtomicUsize, //Workers stuck waiting in next()
+    finished: AtomicBool,
+    exhausted: AtomicBool
 }
 impl LinkList {
-    pub fn new(starting_points: Vec<String>, capacity: u8) -> LinkList {
-        LinkList { links: parking_lot::RwLock::new(VecDeque::from(starting_points)), alert: RwCondvar::new(), capacity }
+    pub fn new(starting_points: Vec<FrontierEntry>, workers: u16) -> LinkList {
+        LinkList { links: RwLock::new(VecDeque::from(starting_points)), alert: Notify::new(), capacity: workers as usize,
+            live: AtomicUsize::new(workers as usize), idle: AtomicUsize::new(0), finished: AtomicBool::new(false), exhausted: AtomicBool::new(false) }
     }
-    pub fn next(&self) -> String {
-        let mut links = self.links.write();
-        while links.is_empty() { //Be VERY careful here - waitw contains deadlocking risk
-            self.alert.waitw(&mut links);
+    ///Waits for the next public url, returning None once the crawl is over. If every worker ends up waiting
+    ///here, nobody is left to find more links, so the frontier is exhausted and the crawl ends.
+    pub async fn next(&self) -> Option<FrontierEntry> {
+        loop {
+            //Grab the notification future BEFORE checking, so an add between the check and the await isn't missed
+            let notified = self.alert.notified();
+            let _idle = {
+                let mut links = self.links.write();
+                if self.finished.load(AtomicOrdering::SeqCst) { return None }
+                if let Some(next_url) = links.pop_front() {
+                    if !links.is_empty() { self.alert.notify_one(); }
+                    return Some(next_url);
+                }
+                if self.idle.fetch_add(1, AtomicOrdering::SeqCst) + 1 == self.live.load(AtomicOrdering::SeqCst) { self.exhaust_locked(); }
+                IdleGuard(self) //Counts us as idle until we're woken back up (or cancelled)
+            }; //Lock must be dropped before awaiting
+            notified.await;
         }
-        let next_url = links.pop_front().unwrap();
-        if !links.is_empty() { self.alert.notify_one(); }
+    }
+    ///Grabs the next public url without waiting for one
+    pub fn try_next(&self) -> Option<FrontierEntry> {
+        if self.is_finished() { return None }
+        let mut links = self.links.write();
+        let next_url = links.pop_front();
+        if !links.is_empty() { self.alert.notify_one(); } //Same as next() - pass the wakeup along to whoever's waiting
         next_url
     }
-    pub fn add(&self, new_links: Vec<String>) {
+    ///Resolves when new links are added (or the crawl ends) - doesn't count as idling
+    pub async fn wait_for_links(&self) { self.alert.notified().await }
+    pub fn add(&self, new_links: Vec<FrontierEntry>) {
         if !new_links.is_empty() {
             let mut links = self.links.write();
-            if links.len() + new_links.len() > self.capacity as usize { eprintln!("Adding beyond capacity, shouldn't do this") } //Checks for intended behavior 
+            if links.len() + new_links.len() > self.capacity { eprintln!("Adding beyond capacity, shouldn't do this") } //Checks for intended behavior 
             links.extend(new_links);
             self.alert.notify_one();
         }
     }
-    pub fn should_add(&self) -> u8 {
+    pub fn should_add(&self) -> usize {
         let links = self.links.read();
-        self.capacity - links.len() as u8
+        self.capacity.saturating_sub(links.len())
+    }
+    ///Takes every link still waiting in the list
+    pub fn drain(&self) -> Vec<FrontierEntry> {
+        let mut links = self.links.write();
+        links.drain(..).collect()
+    }
+    ///Ends the crawl, waking up any workers waiting on a url so they can go home
+    pub fn finish(&self) {
+        let _links = self.links.write();
+        self.finish_locked();
+    }
+    ///Counts a worker out of the crawl when dropped - however it leaves, so one panicking can't leave the rest waiting forever
+    pub fn clock_in(self: &Arc<Self>) -> ShiftGuard { ShiftGuard(self.clone()) }
+    fn exhaust_locked(&self) {
+        self.exhausted.store(true, AtomicOrdering::SeqCst);
+        self.finish_locked();
+    }
+    fn finish_locked(&self) {
+        self.finished.store(true, AtomicOrdering::SeqCst);
+        self.alert.notify_waiters();
+    }
+    pub fn is_finished(&self) -> bool { self.finished.load(AtomicOrdering::SeqCst) }
+    ///Whether the crawl ended because there was nothing left to crawl
+    pub fn is_exhausted(&self) -> bool { self.exhausted.load(AtomicOrdering::SeqCst) }
+}
+
+pub struct ShiftGuard(Arc<LinkList>);
+impl Drop for ShiftGuard {
+    fn drop(&mut self) {
+        let _links = self.0.links.write();
+        let live = self.0.live.fetch_sub(1, AtomicOrdering::SeqCst) - 1;
+        //Everyone still around may be waiting on links only the leaver could have found
+        if live == self.0.idle.load(AtomicOrdering::SeqCst) && !self.0.is_finished() { self.0.exhaust_locked(); }
+    }
+}
+
+struct IdleGuard<'a>(&'a LinkList);
+impl Drop for IdleGuard<'_> {
+    fn drop(&mut self) {
+        let _links = self.0.links.write();
+        self.0.idle.fetch_sub(1, AtomicOrdering::SeqCst);
     }
 }
 
 pub struct LocalUrls {
-    urls: Vec<String>, 
+    urls: Vec<FrontierEntry>, 
+    deferred: Vec<(Instant, FrontierEntry)>, //Urls whose host wasn't ready yet, with when to try again
     last_domain: Option<String>,
     iter_cnt: u8,
     idx: usize,
-    rand_rng: ThreadRng
+    rand_rng: StdRng //ThreadRng isn't Send, so it can't be held across awaits
 }
 impl LocalUrls {
     pub fn new() -> LocalUrls {
-        LocalUrls { urls: Vec::new(), last_domain: None, iter_cnt: 0, idx: 0, rand_rng: rand::thread_rng() }
+        LocalUrls { urls: Vec::new(), deferred: Vec::new(), last_domain: None, iter_cnt: 0, idx: 0, rand_rng: StdRng::from_entropy() }
     }
-    pub fn next(&mut self) -> Option<String> {
+    pub fn next(&mut self) -> Option<FrontierEntry> {
+        //Deferred urls whose wait is up take priority
+        let now = Instant::now();
+        if let Some(pos) = self.deferred.iter().position(|(ready_at, _)| *ready_at <= now) {
+            return Some(self.deferred.swap_remove(pos).1);
+        }
         if self.urls.is_empty() { return None }
         //Let's shake things up! Completely change the current location in the local list of links periodically
         self.iter_cnt += 1;
@@ -109,14 +294,11 @@ impl LocalUrls {
         }
         //If the next url to search has the same domain as the previous one, skip ahead a little
         let next_url = &self.urls[self.idx];
-        match self.last_domain.clone() {
-            Some(last_domain) => {
-                if LocalUrls::domain(next_url) == last_domain {
-                    self.incr(SKIP_AMOUNT);
-                    //next_url = &self.urls[self.idx];
-                }
+        if let Some(last_domain) = &self.last_domain {
+            if next_url.url.host() == last_domain.as_str() {
+                self.incr(SKIP_AMOUNT);
+                //next_url = &self.urls[self.idx];
             }
-            None => {}
         }
         //Remove and return url
         let next_url = self.urls.remove(self.idx);
@@ -130,48 +312,95 @@ impl LocalUrls {
             self.idx -= self.urls.len()
         }
     }
-    fn domain(url: &String) -> String {
-        let domain_match = Regex::new(r"^.+(/|$)").unwrap();
-        match domain_match.find(&url) {
-            Some(mat) => mat.as_str().to_string(),
-            None => panic!("No domain in input string - this should never happen")
-        }
-    }
-    pub fn extend(&mut self, urls: Vec<String>) {
+    pub fn extend(&mut self, urls: Vec<FrontierEntry>) {
         self.urls.extend(urls)
     }
-    pub fn len(&self) -> usize {
-        self.urls.len()
+    ///Sets a url aside until its host is ready for another request
+    pub fn defer(&mut self, url: FrontierEntry, ready_at: Instant) {
+        self.deferred.push((ready_at, url))
     }
-}
-
-//CREDIT: Amanieu in https://github.com/Amanieu/parking_lot/issues/165 for the following - I have no idea why std::Convar doesn't support RwLocks:
-struct RwCondvar {
-    cv: Condvar,
-    mtx: Mutex<()>
-}
-impl RwCondvar {
-    pub fn new() -> RwCondvar {
-        RwCondvar { cv: Condvar::new(), mtx: Mutex::new(()) }
+    ///When the soonest deferred url becomes ready, if there are any
+    pub fn next_ready_at(&self) -> Option<Instant> {
+        self.deferred.iter().map(|(ready_at, _)| *ready_at).min()
     }
-    pub fn waitw<T>(&self, gaurd: &mut parking_lot::RwLockWriteGuard<'_, T>) {
-        let mut locked = self.mtx.lock();
-        parking_lot::RwLockWriteGuard::unlocked(gaurd, || {
-            self.cv.wait(&mut locked);
-        })
+    ///Gives up every url we were still holding onto
+    pub fn into_entries(self) -> Vec<FrontierEntry> {
+        self.urls.into_iter().chain(self.deferred.into_iter().map(|(_, entry)| entry)).collect()
     }
-    pub fn notify_one(&self) -> bool { self.cv.notify_one() }
-    //pub fn notify_all(&self) -> usize { self.cv.notify_all() }
 }
 
+#[derive(Default)]
 pub struct ParsedPage {
     pub data: PageData,
-    pub links: Vec<String> //List of cleansed urls
+    pub links: Vec<FoundLink>,
+    pub directives: RobotsDirectives,
+    pub canonical: Option<CanonicalUrl> //Where the page should be stored, if not under the url we asked for
 }
 
+///A link found on a page
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FoundLink {
+    pub url: CanonicalUrl,
+    pub anchor: Option<String>,
+    pub nofollow: bool //rel="nofollow"
+}
+
+#[derive(Default, Clone, Debug)]
 pub struct PageData {
-    pub title: Option<String>
-    //Could be expanded...
+    pub title: Option<String>,
+    pub body_text: Option<String>, //Visible text, whitespace squashed and truncated
+    //Everything below is about the fetch itself, and gets filled in by the crawler rather than the parser
+    pub status: u16,
+    pub final_url: String, //Where we ended up after redirects
+    pub redirect_chain: Vec<CanonicalUrl>, //Every url we were redirected from, starting with the one we asked for
+    pub content_type: Option<String>,
+    pub content_length: u64, //In bytes
+    pub fetched_at: i64, //Unix seconds
+    pub latency: Duration,
+    pub depth: u32,
+    pub referrer: Option<CanonicalUrl>,
+    pub content_hash: Option<String>, //Hex SHA-256 of the body, if we read all of it
+    pub raw_body: Option<Vec<u8>> //The body as downloaded, only kept with --keep-raw
+}
+
+///Default cap on how much body text gets kept per page, in characters
+pub const DEFAULT_MAX_TEXT: usize = 10_000;
+
+///How pages get picked apart
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    pub filter: QueryFilter,
+    pub max_text: usize //Max characters of body text to keep (0 keeps none)
+}
+impl Default for ParseOptions {
+    fn default() -> ParseOptions { ParseOptions { filter: QueryFilter::default(), max_text: DEFAULT_MAX_TEXT } }
+}
+
+///MIME types we parse by default - everything else just gets its type recorded
+pub const DEFAULT_ACCEPTED_TYPES: &[&str] = &["text/html", "application/xhtml+xml"];
+///Default cap on how big a body we'll read, in bytes
+pub const DEFAULT_MAX_BODY: u64 = 5 * 1024 * 1024;
+
+///What we'll download and parse
+#[derive(Clone, Debug)]
+pub struct FetchOptions {
+    pub accepted_types: Vec<String>, //May end in /* to match a whole type, i.e. text/*
+    pub max_body: u64, //In bytes
+    pub keep_raw: bool //Hang onto the bodies of pages we parse
+}
+impl FetchOptions {
+    ///Whether a Content-Type is one we parse. Servers that don't say get the benefit of the doubt.
+    pub fn accepts(&self, content_type: Option<&str>) -> bool {
+        let Some(content_type) = content_type else { return true };
+        let mime = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+        self.accepted_types.iter().any(|accepted| match accepted.to_lowercase().strip_suffix("/*") {
+            Some(main_type) => mime.split('/').next() == Some(main_type),
+            None => mime == accepted.to_lowercase()
+        })
+    }
+}
+impl Default for FetchOptions {
+    fn default() -> FetchOptions { FetchOptions { accepted_types: DEFAULT_ACCEPTED_TYPES.iter().map(|mime| mime.to_string()).collect(), max_body: DEFAULT_MAX_BODY, keep_raw: false } }
 }
 
 #[derive(Clone)]
@@ -186,37 +415,161 @@ impl DispOptions {
 }
 
 pub struct WorkerData {
-    pub outstanding: usize,
+    pub outstanding: Vec<FrontierEntry>,
     pub req_time: Duration, 
     pub tot_time: Duration, 
+    pub nofollow_pages: usize, //Pages whose links we didn't follow
+    pub nofollow_links: usize, //rel="nofollow" links we didn't follow
+    pub retries: usize
 }
 impl WorkerData {
-    pub fn new(outstanding: usize, req_time: Duration, tot_time: Duration) -> WorkerData {
-        WorkerData { outstanding, req_time, tot_time }
+    pub fn new(outstanding: Vec<FrontierEntry>, req_time: Duration, tot_time: Duration) -> WorkerData {
+        WorkerData { outstanding, req_time, tot_time, nofollow_pages: 0, nofollow_links: 0, retries: 0 }
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::canonical_url::QueryFilter;
+    fn entry(url: &str) -> FrontierEntry { FrontierEntry::start(CanonicalUrl::parse(url, &QueryFilter::default()).unwrap()) }
+    //LinkList Tests
+    #[tokio::test]
+    async fn link_list_exhausts() {
+        let links = Arc::new(LinkList::new(vec![entry("https://gwango.lol/")], 2));
+        assert_eq!(links.next().await, Some(entry("https://gwango.lol/")));
+        let waiter = tokio::spawn({
+            let links = links.clone();
+            async move { links.next().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!links.is_finished()); //Only one of two workers is idle
+        assert_eq!(links.next().await, None);
+        assert_eq!(waiter.await.unwrap(), None);
+        assert!(links.is_exhausted());
+    }
+    #[tokio::test]
+    async fn link_list_survives_panics() {
+        let links = Arc::new(LinkList::new(Vec::new(), 2));
+        let waiter = tokio::spawn({
+            let links = links.clone();
+            async move {
+                let _shift = links.clock_in();
+                links.next().await
+            }
+        });
+        let panicker = tokio::spawn({
+            let links = links.clone();
+            async move {
+                let _shift = links.clock_in();
+                panic!("Oh no")
+            }
+        });
+        assert!(panicker.await.is_err());
+        //The one worker left is idle, so the crawl's over
+        assert_eq!(tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap(), None);
+        assert!(links.is_exhausted());
+    }
+    #[tokio::test]
+    async fn link_list_wakes_on_add() {
+        let links = Arc::new(LinkList::new(Vec::new(), 2));
+        let waiter = tokio::spawn({
+            let links = links.clone();
+            async move { links.next().await }
+        });
+        tokio::task::yield_now().await;
+        links.add(vec![entry("https://gwango.lol/")]);
+        assert_eq!(waiter.await.unwrap(), Some(entry("https://gwango.lol/")));
+        assert!(!links.is_finished());
+    }
+    #[tokio::test]
+    async fn link_list_finish() {
+        let links = LinkList::new(vec![entry("https://gwango.lol/")], 2);
+        links.finish();
+        assert_eq!(links.next().await, None);
+        assert!(!links.is_exhausted());
+    }
+    #[tokio::test]
+    async fn link_list_try_next_passes_wakeup() {
+        let links = Arc::new(LinkList::new(vec![entry("https://gwango.lol/"), entry("https://google.com/")], 2));
+        let waiter = tokio::spawn({
+            let links = links.clone();
+            async move { links.wait_for_links().await }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(links.try_next(), Some(entry("https://gwango.lol/")));
+        //One link is left, so whoever's waiting hears about it
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+    }
+    #[test]
+    fn link_list_drain() {
+        let links = LinkList::new(vec![entry("https://gwango.lol/"), entry("https://google.com/")], 2);
+        assert_eq!(links.drain().len(), 2);
+        assert_eq!(links.try_next(), None);
+    }
     //LocalURls Tests
     #[test]
     fn next_simple() {
         let mut lu = LocalUrls::new();
-        let urls = ["gwango.lol/hello", "google.com/"].to_vec().iter().map(|url| url.to_string()).collect();
+        let urls = ["https://gwango.lol/hello", "https://google.com/"].to_vec().iter().map(|url| entry(url)).collect();
         lu.extend(urls);
-        assert_eq!(lu.next(), Some("gwango.lol/hello".to_string()));
-        assert_eq!(lu.next(), Some("google.com/".to_string()));
+        assert_eq!(lu.next(), Some(entry("https://gwango.lol/hello")));
+        assert_eq!(lu.next(), Some(entry("https://google.com/")));
     }
     #[test]
     fn next_complex() {
         let mut lu = LocalUrls::new();
-        let urls: Vec<String> = ["gwango.lol/hello", "gwango.lol/", "google.com/", "yahoo.com/stuff", "other_site/thingy"].to_vec().iter().map(|url| url.to_string()).collect();
+        let urls: Vec<FrontierEntry> = ["https://gwango.lol/hello", "https://gwango.lol/", "https://google.com/", "https://yahoo.com/stuff", "https://other_site/thingy"].to_vec().iter().map(|url| entry(url)).collect();
         lu.extend(urls.clone());
-        assert_eq!(lu.next(), Some("gwango.lol/hello".to_string()));
+        assert_eq!(lu.next(), Some(entry("https://gwango.lol/hello")));
         let mut idx = 1 + SKIP_AMOUNT;
         while idx >= urls.len() {
             idx -= urls.len();
         }
-        assert_eq!(lu.next(), Some(urls[idx].to_string()));
+        assert_eq!(lu.next(), Some(urls[idx].clone()));
+    }
+    #[test]
+    fn next_deferred() {
+        let mut lu = LocalUrls::new();
+        lu.extend(vec![entry("https://google.com/")]);
+        lu.defer(entry("https://gwango.lol/later"), Instant::now() + Duration::from_secs(3600));
+        lu.defer(entry("https://gwango.lol/now"), Instant::now());
+        assert_eq!(lu.next(), Some(entry("https://gwango.lol/now")));
+        assert_eq!(lu.next(), Some(entry("https://google.com/")));
+        assert_eq!(lu.next(), None); //Still waiting on gwango.lol/later
+        assert!(lu.next_ready_at().is_some());
+        assert_eq!(lu.into_entries(), vec![entry("https://gwango.lol/later")]);
+    }
+    //FrontierEntry Tests
+    #[test]
+    fn child_entry() {
+        let parent = entry("https://gwango.lol/");
+        let child = parent.child(CanonicalUrl::parse("https://google.com/", &QueryFilter::default()).unwrap());
+        assert_eq!(child.depth, 1);
+        assert_eq!(child.referrer, Some(parent.url));
+    }
+    //ErrorKind Tests
+    #[test]
+    fn error_kinds() {
+        let status = |code: u16| Error::new(FetchError { kind: ErrorKind::Status(code), retry_after: None, msg: String::new() });
+        assert_eq!(ErrorKind::classify(&status(503)), ErrorKind::Status(503));
+        assert!(ErrorKind::classify(&status(503)).is_transient());
+        assert!(ErrorKind::classify(&status(429)).is_transient());
+        assert!(!ErrorKind::classify(&status(404)).is_transient());
+        assert_eq!(ErrorKind::classify(&Error::msg("who knows")), ErrorKind::Other);
+        assert!(ErrorKind::Timeout.is_transient() && !ErrorKind::Redirect.is_transient());
+    }
+    //FetchOptions Tests
+    #[test]
+    fn accepts_types() {
+        let opts = FetchOptions::default();
+        assert!(opts.accepts(Some("text/html; charset=utf-8")));
+        assert!(opts.accepts(Some("Application/XHTML+XML")));
+        assert!(opts.accepts(None));
+        assert!(!opts.accepts(Some("application/pdf")));
+        assert!(!opts.accepts(Some("text/htmlx")));
+        let opts = FetchOptions { accepted_types: vec!["text/*".to_string()], ..FetchOptions::default() };
+        assert!(opts.accepts(Some("text/plain")));
+        assert!(!opts.accepts(Some("image/png")));
     }
 }
\ No newline at end of file
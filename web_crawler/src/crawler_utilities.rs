@@ -1,66 +1,184 @@
 use crate::prelude::*;
 use crate::crawler_datatypes::*;
-use crate::url_tree::valid_url_char;
+use crate::canonical_url::{CanonicalUrl, QueryFilter};
+use crate::robots::{RobotsDirectives, ROBOTS_TOKEN};
+use url::Url;
+use rand::Rng;
+use std::time::SystemTime;
+use sha2::{Digest, Sha256};
+use scraper::{Html, HtmlTreeSink, Node, Selector, node::Element};
+use ego_tree::NodeRef;
+use html5ever::{ParseOpts, tree_builder::TreeBuilderOpts, tendril::TendrilSink};
 
-pub fn parse_page(page: String) -> ParsedPage {
+const MAX_ANCHOR_CHARS: usize = 200;
+///First retry waits about this long, doubling each time after
+const RETRY_BASE: Duration = Duration::from_secs(1);
+///Longest we'll wait to retry a page (and the longest Retry-After we'll put up with)
+pub const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+///Elements skipped over when pulling out a page's text
+const SKIPPED_ELEMENTS: &[&str] = &["head", "script", "style", "template", "svg", "canvas", "iframe", "object", "nav", "aside", "button", "select"];
+///Skipped too, but only outside of <article>/<main> - in there they belong to the content (a post's byline, say)
+const PAGE_CHROME_ELEMENTS: &[&str] = &["header", "footer"];
+///Elements holding a page's actual content
+const CONTENT_ELEMENTS: &[&str] = &["article", "main"];
+///ARIA roles that mark the same sorts of boilerplate
+const SKIPPED_ROLES: &[&str] = &["navigation", "banner", "contentinfo", "complementary", "search"];
+///Namespace the parser puts ordinary HTML elements in (as opposed to SVG or MathML ones)
+const HTML_NAMESPACE: &str = "http://www.w3.org/1999/xhtml";
+///Elements that don't split up words, unlike everything else
+const INLINE_ELEMENTS: &[&str] = &["a", "abbr", "b", "bdi", "bdo", "cite", "code", "data", "dfn", "em", "i", "kbd", "mark", "q", "s", "samp", "small", "span", "strong", "sub", "sup", "time", "u", "var"];
+
+///Parses a page fetched from `page_url`
+pub fn parse_page(page: String, page_url: &CanonicalUrl, opts: &ParseOptions) -> ParsedPage {
+    let dom = parse_html(&page);
     ParsedPage {
-        data: PageData { title: find_title(&page) },
-        links: find_links(&page)
+        data: PageData { title: find_title(&dom), body_text: find_body_text(&dom, opts.max_text), ..PageData::default() },
+        links: find_links(&dom, page_url.as_str(), &opts.filter),
+        directives: find_meta_robots(&dom),
+        canonical: find_canonical(&dom, page_url.as_str(), &opts.filter)
     }
 }
 
-///Finds the titls of a webpage, returning None if it cannot find a title
-pub fn find_title(page: &String) -> Option<String> {
-    let title_match = Regex::new(r"<title\s*.*?>.+?</title>").unwrap();
-    match title_match.find(page) {
-        Some(title) => {
-            let extract_match = Regex::new(r">.+?<").unwrap();
-            let innards = extract_match.find(title.as_str()).unwrap().as_str();
-            let innards = &innards[1..innards.len()-1];
-            Some(innards.to_string())
-        }
-        None => None
+///Builds a DOM out of a page the way a browser would, however broken the markup. We don't run scripts,
+///so scripting is off and <noscript> content is parsed as regular markup - it's what we'd be shown.
+pub fn parse_html(page: &str) -> Html {
+    let opts = ParseOpts { tree_builder: TreeBuilderOpts { scripting_enabled: false, ..Default::default() }, ..Default::default() };
+    html5ever::parse_document(HtmlTreeSink::new(Html::new_document()), opts).one(page)
+}
+
+///Finds the title of a webpage, returning None if it cannot find a (non-empty) title. Only an HTML <title> counts -
+///SVG and MathML have title elements of their own (tooltips, mostly), which aren't the page's.
+pub fn find_title(dom: &Html) -> Option<String> {
+    let title_select = Selector::parse("title").unwrap();
+    let is_foreign = |node: NodeRef<Node>| node.value().as_element().is_some_and(|elem| &*elem.name.ns != HTML_NAMESPACE || matches!(elem.name(), "svg" | "math"));
+    dom.select(&title_select).find(|title| !is_foreign(**title) && !title.ancestors().any(is_foreign)).and_then(|title| collapse_text(title.text(), usize::MAX))
+}
+
+///Finds all links contained within a webpage, resolves them against the page's base url, cleans them, and returns a vector of them
+pub fn find_links(dom: &Html, page_url: &str, filter: &QueryFilter) -> Vec<FoundLink> {
+    let link_select = Selector::parse("a[href]").unwrap();
+    let Some(base) = find_base(dom, page_url) else { return Vec::new() };
+    dom.select(&link_select).filter_map(|link| {
+        let url = resolve_url(&base, link.attr("href")?).and_then(|url| cleanse_url(&url, filter))?;
+        let nofollow = link.attr("rel").is_some_and(|rel| rel.split_ascii_whitespace().any(|val| val.eq_ignore_ascii_case("nofollow")));
+        Some(FoundLink { url, anchor: collapse_text(link.text(), MAX_ANCHOR_CHARS), nofollow })
+    }).collect()
+}
+
+///Pulls out the text a reader would actually see on a page - no scripts, styles, menus or headers/footers
+pub fn find_body_text(dom: &Html, max_chars: usize) -> Option<String> {
+    if max_chars == 0 { return None }
+    let mut text = Vec::new();
+    for child in dom.root_element().children() { push_visible_text(child, false, &mut text); }
+    collapse_text(text.into_iter(), max_chars)
+}
+
+///Adds the visible text under a node to `text`, skipping over anything that isn't content
+fn push_visible_text<'a>(node: NodeRef<'a, Node>, in_content: bool, text: &mut Vec<&'a str>) {
+    match node.value() {
+        Node::Text(words) => text.push(words),
+        Node::Element(elem) if !is_boilerplate(elem, in_content) => {
+            let breaks = !INLINE_ELEMENTS.contains(&elem.name());
+            let in_content = in_content || CONTENT_ELEMENTS.contains(&elem.name());
+            if breaks { text.push(" "); } //So <p>a</p><p>b</p> doesn't come out as "ab"
+            for child in node.children() { push_visible_text(child, in_content, text); }
+            if breaks { text.push(" "); }
+        },
+        _ => {}
     }
 }
 
-///Finds all links contained within a webpage, cleans them, and returns a vector of them
-pub fn find_links(page: &String) -> Vec<String> {
-    let link_match = Regex::new(r#"<a.+?href=("|').+?("|').*?>"#).unwrap();
-    let mut urls = Vec::new();
-    link_match.find_iter(page).for_each(|mat| {
-        let extract_match = Regex::new(r#"href=("|').+?("|')"#).unwrap();
-        let innards = extract_match.find(mat.as_str()).unwrap().as_str();
-        let url_str = &innards[6..innards.len()-1]; //Eliminates href=" and the closing " from the string
-        if let Some(cleansed) = cleanse_url(url_str) {
-            urls.push(cleansed)
+///Elements whose text isn't part of the page's content
+fn is_boilerplate(elem: &Element, in_content: bool) -> bool {
+    SKIPPED_ELEMENTS.contains(&elem.name())
+        || (!in_content && PAGE_CHROME_ELEMENTS.contains(&elem.name()))
+        || elem.attr("hidden").is_some()
+        || elem.attr("aria-hidden").is_some_and(|val| val.eq_ignore_ascii_case("true"))
+        || elem.attr("role").is_some_and(|role| SKIPPED_ROLES.iter().any(|skipped| role.eq_ignore_ascii_case(skipped)))
+}
+
+///Joins up bits of text with runs of whitespace squashed to single spaces, cut off at `max_chars`.
+///Returns None if there's nothing but whitespace.
+fn collapse_text<'a>(text: impl Iterator<Item = &'a str>, max_chars: usize) -> Option<String> {
+    let joined: String = text.collect();
+    let collapsed: String = joined.split_whitespace().collect::<Vec<&str>>().join(" ").chars().take(max_chars).collect();
+    if collapsed.is_empty() { None } else { Some(collapsed) }
+}
+
+///Gathers up the directives in a page's <meta name="robots"> tags (and any addressed to us by name)
+pub fn find_meta_robots(dom: &Html) -> RobotsDirectives {
+    let meta_select = Selector::parse("meta[name][content]").unwrap();
+    let mut directives = RobotsDirectives::default();
+    for meta in dom.select(&meta_select) {
+        let name = meta.attr("name").unwrap_or_default().trim();
+        if name.eq_ignore_ascii_case("robots") || name.eq_ignore_ascii_case(ROBOTS_TOKEN) {
+            directives.add(meta.attr("content").unwrap_or_default());
         }
-    });
-    urls
+    }
+    directives
 }
 
-///Cleanses an input url by removing http://, any trailing queries (?thing=sfd...), and whitespace
-///Returns None if not a valid url
-pub fn cleanse_url(url: &str) -> Option<String> {
-    let proto_match  = Regex::new(r"https?://").unwrap();
-    let query_match = Regex::new(r"\?.*").unwrap();
-    if proto_match.is_match(url) {
-        let cut_proto = proto_match.replace(url, "");
-        let cut_query = query_match.replace(&cut_proto, "");
-        let trimmed = cut_query.trim().to_string();
-        if valid_url(&trimmed) { Some(trimmed) }
-        else { None }
-    } else { None }
+///Finds the page's <link rel="canonical">, if it has a usable one. A page only gets to name a url on its own site -
+///otherwise anyone could file their page under somebody else's url.
+pub fn find_canonical(dom: &Html, page_url: &str, filter: &QueryFilter) -> Option<CanonicalUrl> {
+    let link_select = Selector::parse("link[rel][href]").unwrap();
+    let base = find_base(dom, page_url)?;
+    let canonical = dom.select(&link_select).find(|link| link.attr("rel").unwrap_or_default().split_ascii_whitespace().any(|rel| rel.eq_ignore_ascii_case("canonical")))?;
+    let canonical = resolve_url(&base, canonical.attr("href")?).and_then(|url| cleanse_url(&url, filter))?;
+    let page_url = CanonicalUrl::parse(page_url, filter)?;
+    if same_site(page_url.host(), canonical.host()) { Some(canonical) } else { None }
 }
 
-///Turns a cleansed url into a usable url
-pub fn dirty_url(cleansed_url: &String) -> String { format!("https://{cleansed_url}") }
+///Whether a page on `host` can speak for `other` - the same host give or take a "www.", or one of its subdomains.
+///Parent domains don't count, since without the public suffix list we can't tell gwango.lol from github.io.
+fn same_site(host: &str, other: &str) -> bool {
+    let strip_www = |host: &str| host.strip_prefix("www.").unwrap_or(host).to_string();
+    let (host, other) = (strip_www(host), strip_www(other));
+    other == host || other.ends_with(&format!(".{host}"))
+}
 
-///Ensures a cleansed url contains only valid characters
-pub fn valid_url(url: &String) -> bool {
-    for ch in url.chars() {
-        if !valid_url_char(ch) { return false; }
+///Finds the url relative links on a page are resolved against - the page's own url unless a <base href> says otherwise
+pub fn find_base(dom: &Html, page_url: &str) -> Option<Url> {
+    let page_url = Url::parse(page_url).ok()?;
+    let base_select = Selector::parse("base[href]").unwrap();
+    match dom.select(&base_select).next().and_then(|base| base.attr("href")) {
+        Some(href) => Some(page_url.join(href.trim()).unwrap_or(page_url)),
+        None => Some(page_url)
     }
-    true
+}
+
+///Resolves a (possibly relative) reference against a base url per RFC 3986, returning None if it can't be
+pub fn resolve_url(base: &Url, reference: &str) -> Option<String> {
+    base.join(reference.trim()).ok().map(String::from)
+}
+
+///Cleanses an input url into its canonical form (see CanonicalUrl), returning None if it isn't a valid http(s) url.
+///The url parser is forgiving about slashes (`http:/a.com` is fine by it), so we insist on a proper `scheme://` here.
+pub fn cleanse_url(url: &str, filter: &QueryFilter) -> Option<CanonicalUrl> {
+    let trimmed = url.trim();
+    if trimmed.starts_with("http://") || trimmed.starts_with("https://") { CanonicalUrl::parse(trimmed, filter) }
+    else { None }
+}
+
+///How long to wait before retry number `attempt` (counting from 0). It's exponential, with jitter so a bunch of
+///pages that failed together don't all come back together. If the server said when to come back, we do that.
+pub fn retry_delay(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    if let Some(wait) = retry_after { return wait }
+    let ceiling = RETRY_BASE.saturating_mul(1 << attempt.min(16)).min(MAX_RETRY_WAIT);
+    ceiling.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+///Reads a Retry-After header, which is either a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() { return Some(Duration::from_secs(secs)) }
+    let when = httpdate::parse_http_date(value).ok()?;
+    Some(when.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO))
+}
+
+///Hex SHA-256 of some content, for spotting duplicate and changed pages
+pub fn content_hash(content: &[u8]) -> String {
+    Sha256::digest(content).iter().map(|byte| format!("{byte:02x}")).collect()
 }
 
 ///Makeshift logger
@@ -77,56 +195,200 @@ pub fn make_disp(opts: DispOptions) -> impl Fn(String, u8) {
 mod tests {
     use super::*;
     //find_title Tests
+    fn title(page: &str) -> Option<String> { find_title(&parse_html(page)) }
     #[test]
     fn find_title_simple() {
         let page = "stuff stuff stuff more stuff <title>Title</title> and more stuff out here too".to_string();
-        assert_eq!(find_title(&page), Some(String::from("Title")));
+        assert_eq!(title(&page), Some(String::from("Title")));
     }
     #[test]
     fn find_title_complex() {
+        //End tags are case-insensitive, so the title is closed straight away and is empty
         let page = "stuff stuff <a href=\"gwango.lol\">yeah</a> more stuff <title attr></tItle> </title> and more stuff out here too".to_string();
-        assert_eq!(find_title(&page), Some(String::from("</tItle> ")));
+        assert_eq!(title(&page), None);
     }
     #[test]
     fn find_title_no_title() {
+        let page = "stuff stuff more stuff <p>Title</p> and more stuff out here too".to_string();
+        assert_eq!(title(&page), None);
+    }
+    #[test]
+    fn find_title_unclosed() {
+        //Like a browser, an unclosed title runs to the end of the page
         let page = "stuff stuff more stuff <title>Title<title> and more stuff out here too".to_string();
-        assert_eq!(find_title(&page), None);
+        assert_eq!(title(&page), Some(String::from("Title<title> and more stuff out here too")));
+    }
+    #[test]
+    fn find_title_skips_svg() {
+        let page = "<body><svg><title>Tooltip</title></svg><math><title>Formula</title></math></body>";
+        assert_eq!(title(page), None);
+        assert_eq!(title(&format!("{page}<title>Real</title>")), Some("Real".to_string()));
+    }
+    #[test]
+    fn find_title_entities() {
+        let page = "<!-- <title>Old</title> --><script>document.title = '<title>JS</title>'</script><title>\n  Fish &amp; Chips &lt;3 &#x1F41F;\n</title>";
+        assert_eq!(title(page), Some(String::from("Fish & Chips <3 \u{1F41F}")));
+    }
+    //find_body_text Tests
+    fn body_text(page: &str, max_chars: usize) -> Option<String> { find_body_text(&parse_html(page), max_chars) }
+    #[test]
+    fn find_body_text_simple() {
+        let page = "<html><head><title>Title</title><style>p { color: red }</style></head><body><p>Hello,\n   <b>wor</b>ld!</p><p>Bye&nbsp;now</p></body></html>";
+        assert_eq!(body_text(page, 100), Some("Hello, world! Bye now".to_string()));
+    }
+    #[test]
+    fn find_body_text_boilerplate() {
+        let page = "<header>Site Name</header><nav><a href=\"/\">Home</a></nav><div role=\"navigation\">Menu</div> \
+            <main>The <em>good</em> stuff<script>alert('hi')</script><span hidden>secret</span></main><!-- comment --> \
+            <aside>Ads</aside><footer>Copyright</footer>";
+        assert_eq!(body_text(page, 100), Some("The good stuff".to_string()));
+    }
+    #[test]
+    fn find_body_text_content_chrome() {
+        //A post's own header and footer are part of the post
+        let page = "<header>Site Name</header><article><header><h1>Post</h1></header><p>Words</p><footer>By me</footer></article><footer>Copyright</footer>";
+        assert_eq!(body_text(page, 100), Some("Post Words By me".to_string()));
+    }
+    #[test]
+    fn find_body_text_form_page() {
+        //Plenty of older sites (ASP.NET especially) wrap the whole page in a form
+        let page = "<body><form action=\"/postback\"><nav>Menu</nav><p>All the content</p><input type=\"hidden\" value=\"junk\"><button>Go</button></form></body>";
+        assert_eq!(body_text(page, 100), Some("All the content".to_string()));
+    }
+    #[test]
+    fn find_body_text_truncates() {
+        let page = "<p>one two three four</p>";
+        assert_eq!(body_text(page, 7), Some("one two".to_string()));
+        assert_eq!(body_text(page, 0), None);
+        assert_eq!(body_text("<script>only js</script>", 100), None);
     }
     //find_links Tests
+    const BASE: &str = "https://base.com/dir/page.html";
+    fn links(page: &str) -> Vec<String> {
+        find_links(&parse_html(page), BASE, &QueryFilter::default()).iter().map(|link| link.url.to_string()).collect()
+    }
     #[test]
     fn find_links_simple() {
         let page = "stuff stuff stuff more stuff <a href=\"https://heybudy\">Title</a> and more stuff out here too".to_string();
-        assert_eq!(find_links(&page), vec!["heybudy".to_string()]);
+        assert_eq!(links(&page), vec!["https://heybudy/".to_string()]);
     }
     #[test]
     fn find_links_complex() {
         let page = "stuff stuff <a attr href=\"gwango.lol\">yeah</a> more stuff <a href='http://wassup.com/its_ya_boy?actually=dont_include_this' attr></tItle <a href='https://please I good url'> </title> and more href=\"  https://weee  \" out here too".to_string();
-        assert_eq!(find_links(&page), vec!["wassup.com/its_ya_boy".to_string()]);
+        assert_eq!(links(&page), vec!["https://base.com/dir/gwango.lol".to_string(), "http://wassup.com/its_ya_boy?actually=dont_include_this".to_string()]);
     }
     #[test]
     fn find_links_no_links() {
         let page = "stuff stuff more stuff <title>Title<title> and more stuff out here too".to_string();
-        assert_eq!(find_links(&page), Vec::<String>::new());
+        assert_eq!(links(&page), Vec::<String>::new());
+    }
+    #[test]
+    fn find_links_relative() {
+        let page = "<a href=\"/about\">a</a> <a href=\"../docs/x.html\">b</a> <a href=\"//cdn.example.com/y\">c</a> <a href=\"mailto:me@base.com\">d</a>";
+        assert_eq!(links(page), vec!["https://base.com/about".to_string(), "https://base.com/docs/x.html".to_string(), "https://cdn.example.com/y".to_string()]);
+    }
+    #[test]
+    fn find_links_base_href() {
+        let page = "<head><BASE target=\"_top\" href=\"https://other.com/sub/\"></head> <a href=\"page.html\">a</a> <a href=\"/root\">b</a>";
+        assert_eq!(links(page), vec!["https://other.com/sub/page.html".to_string(), "https://other.com/root".to_string()]);
+    }
+    #[test]
+    fn find_links_tricky_markup() {
+        let page = "<a href=/unquoted>a</a> <a title=\"1 > 0\" href=\"/gt\">b</a> <a href=\"/q?x=1&amp;y=2\">c</a> \
+            <!-- <a href=\"/commented\">d</a> --> <script>let s = '<a href=\"/scripted\">e</a>';</script> <noscript><a href=\"/noscript\">f</a></noscript>";
+        assert_eq!(links(page), vec!["https://base.com/unquoted", "https://base.com/gt", "https://base.com/q?x=1&y=2", "https://base.com/noscript"]);
+    }
+    #[test]
+    fn find_links_anchor_and_rel() {
+        let page = "<a href=\"/a\">  Some <b>bold</b>\n text </A> <a rel='external NoFollow' href=\"/b\"><img src=\"x.png\"></a> <a href=\"/c\">never closed";
+        let found = find_links(&parse_html(page), BASE, &QueryFilter::default());
+        assert_eq!(found.len(), 3);
+        assert_eq!((found[0].anchor.as_deref(), found[0].nofollow), (Some("Some bold text"), false));
+        assert_eq!((found[1].anchor.as_deref(), found[1].nofollow), (None, true));
+        assert_eq!((found[2].anchor.as_deref(), found[2].nofollow), (Some("never closed"), false));
+    }
+    //find_meta_robots Tests
+    #[test]
+    fn find_meta_robots_simple() {
+        let page = "<head><meta name=\"ROBOTS\" content=\"noindex\"><meta name=\"googlebot\" content=\"nofollow\"></head>";
+        assert_eq!(find_meta_robots(&parse_html(page)), RobotsDirectives { noindex: true, nofollow: false });
+        let page = "<meta name=\"description\" content=\"none\"><meta name=\"web_crawler\" content=\"nofollow\">";
+        assert_eq!(find_meta_robots(&parse_html(page)), RobotsDirectives { noindex: false, nofollow: true });
+        assert_eq!(find_meta_robots(&parse_html("<!-- <meta name=\"robots\" content=\"none\"> -->")), RobotsDirectives::default());
+    }
+    //find_canonical Tests
+    #[test]
+    fn find_canonical_simple() {
+        let canonical = |page: &str| find_canonical(&parse_html(page), BASE, &QueryFilter::default()).map(|url| url.to_string());
+        assert_eq!(canonical("<head><link rel=\"stylesheet\" href=\"a.css\"><link rel=\"Canonical\" href=\"/page?utm_source=x\"></head>"), Some("https://base.com/page".to_string()));
+        assert_eq!(canonical("<link rel=\"canonical\" href=\"javascript:void(0)\">"), None);
+        assert_eq!(canonical("<link rel=\"alternate\" href=\"/fr/\">"), None);
+    }
+    #[test]
+    fn find_canonical_same_site() {
+        let canonical = |page_url: &str, href: &str| find_canonical(&parse_html(&format!("<link rel=\"canonical\" href=\"{href}\">")), page_url, &QueryFilter::default()).map(|url| url.to_string());
+        assert_eq!(canonical("https://gwango.lol/a", "https://www.gwango.lol/a"), Some("https://www.gwango.lol/a".to_string()));
+        assert_eq!(canonical("http://www.gwango.lol/a", "https://gwango.lol/a"), Some("https://gwango.lol/a".to_string()));
+        assert_eq!(canonical("https://gwango.lol/a", "https://blog.gwango.lol/a"), Some("https://blog.gwango.lol/a".to_string()));
+        //Some other site's page (or a parent domain that might belong to someone else) is ignored
+        assert_eq!(canonical("https://evil.lol/a", "https://gwango.lol/a"), None);
+        assert_eq!(canonical("https://evil.github.io/", "https://github.io/"), None);
+        assert_eq!(canonical("https://notgwango.lol/", "https://gwango.lol/"), None);
+    }
+    //resolve_url Tests
+    #[test]
+    fn resolve_url_rfc3986() {
+        //A few of the normal examples from RFC 3986 section 5.4.1
+        let base = Url::parse("http://a/b/c/d;p?q").unwrap();
+        assert_eq!(resolve_url(&base, "g"), Some("http://a/b/c/g".to_string()));
+        assert_eq!(resolve_url(&base, "./g/"), Some("http://a/b/c/g/".to_string()));
+        assert_eq!(resolve_url(&base, "../../g"), Some("http://a/g".to_string()));
+        assert_eq!(resolve_url(&base, "?y"), Some("http://a/b/c/d;p?y".to_string()));
+        assert_eq!(resolve_url(&base, "//g"), Some("http://g/".to_string()));
+    }
+    //Retry Tests
+    #[test]
+    fn retry_delay_backs_off() {
+        for attempt in 0..3 {
+            let delay = retry_delay(attempt, None);
+            assert!(delay >= RETRY_BASE * 2u32.pow(attempt) / 2 && delay <= RETRY_BASE * 2u32.pow(attempt));
+        }
+        assert!(retry_delay(40, None) <= MAX_RETRY_WAIT);
+        assert_eq!(retry_delay(0, Some(Duration::from_secs(7))), Duration::from_secs(7));
+    }
+    #[test]
+    fn parse_retry_after_forms() {
+        assert_eq!(parse_retry_after(" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::ZERO)); //Already passed
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3600));
+        assert!(parse_retry_after(&later).is_some_and(|wait| wait > Duration::from_secs(3500)));
+        assert_eq!(parse_retry_after("soonish"), None);
+    }
+    //content_hash Tests
+    #[test]
+    fn content_hash_simple() {
+        assert_eq!(content_hash(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
     }
     //cleanse_url Tests
+    fn cleanse(url: &str) -> Option<String> { cleanse_url(url, &QueryFilter::default()).map(|url| url.to_string()) }
     #[test]
     fn cleanse_url_simple() {
         let url = "https://gwango.lol".to_string();
-        assert_eq!(cleanse_url(&url), Some("gwango.lol".to_string()));
+        assert_eq!(cleanse(&url), Some("https://gwango.lol/".to_string()));
     }
     #[test]
     fn cleanse_url_complex() {
-        let url = "\n http://cs.gwango.lol/sub-dir/anutha_/page.html?key=val  ".to_string();
-        assert_eq!(cleanse_url(&url), Some("cs.gwango.lol/sub-dir/anutha_/page.html".to_string()));
+        let url = "\n http://cs.gwango.lol/sub-dir/anutha_/page.html?key=val&utm_source=spam#section  ".to_string();
+        assert_eq!(cleanse(&url), Some("http://cs.gwango.lol/sub-dir/anutha_/page.html?key=val".to_string()));
     }
     #[test]
     fn cleanse_url_bad_link() {
         let url = "http:/gwango.lol".to_string();
-        assert_eq!(cleanse_url(&url), None);
+        assert_eq!(cleanse(&url), None);
     }
     #[test]
     fn cleanse_url_bad_chars() {
         let url = "https://gwango lol".to_string();
-        assert_eq!(cleanse_url(&url), None);
+        assert_eq!(cleanse(&url), None);
     }
-}
\ No newline at end of file
+}
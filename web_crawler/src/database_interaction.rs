@@ -1,38 +1,612 @@
 use crate::prelude::*;
-use crate::crawler_datatypes::SiteMap;
+use crate::crawler_datatypes::{PageData, ParsedPage, FrontierEntry, FoundLink, ErrorKind};
+use crate::crawl_store::{CrawlStore, PageRecord, FailureRecord, WriteStats, unix_now};
+use crate::canonical_url::CanonicalUrl;
 use rusqlite::{params, Connection, OpenFlags};
+use flate2::{Compression, write::GzEncoder, read::GzDecoder};
+use std::io::{Read, Write};
 
-pub fn load_db(db_path: &PathBuf, site_map: Arc<SiteMap>) -> Result<()> {
-    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
-    let mut raw_data = conn.prepare("SELECT url, title FROM site")?;
-    let tuple_data = raw_data.query_map([], |row| {
-        let url: String = row.get(0)?;
-        //let title: Option<String> = row.get(1)?;
-        Ok(url)
-    })?;
-    for row in tuple_data {
-        let url = row?;
-        site_map.insert_previously(url);
+///Each entry brings the schema up a version (tracked in SQLite's user_version) - only ever append to this!
+const MIGRATIONS: &[&str] = &[
+    //1: The original tables (site matches what the search app's db.create_all() makes, so its databases migrate cleanly)
+    "CREATE TABLE IF NOT EXISTS site (url VARCHAR(100) NOT NULL PRIMARY KEY, title VARCHAR(50));
+     CREATE TABLE IF NOT EXISTS frontier (url TEXT NOT NULL PRIMARY KEY, depth INTEGER NOT NULL, referrer TEXT);",
+    //2: When each page was last crawled (unix seconds), so re-crawls refresh rows instead of clashing with them
+    "ALTER TABLE site ADD COLUMN last_crawled INTEGER;",
+    //3: Everything else we know about a fetch, for debugging crawls and ranking results
+    "ALTER TABLE site ADD COLUMN status INTEGER;
+     ALTER TABLE site ADD COLUMN final_url TEXT;
+     ALTER TABLE site ADD COLUMN content_type TEXT;
+     ALTER TABLE site ADD COLUMN content_length INTEGER;
+     ALTER TABLE site ADD COLUMN fetched_at INTEGER;
+     ALTER TABLE site ADD COLUMN latency_ms INTEGER;
+     ALTER TABLE site ADD COLUMN depth INTEGER;
+     ALTER TABLE site ADD COLUMN referrer TEXT;
+     ALTER TABLE site ADD COLUMN content_hash TEXT;",
+    //4: The link graph - one row per distinct source -> target edge
+    "CREATE TABLE IF NOT EXISTS link (source TEXT NOT NULL, target TEXT NOT NULL, anchor TEXT, nofollow INTEGER NOT NULL DEFAULT 0, PRIMARY KEY (source, target));
+     CREATE INDEX IF NOT EXISTS link_target ON link (target);",
+    //5: Visible page text, for searching page contents
+    "ALTER TABLE site ADD COLUMN body_text TEXT;",
+    //6: Urls that are stored under another url - redirect chains, and pages naming a different canonical url
+    "CREATE TABLE IF NOT EXISTS alias (url TEXT NOT NULL PRIMARY KEY, target TEXT NOT NULL, hop INTEGER NOT NULL);
+     CREATE INDEX IF NOT EXISTS alias_target ON alias (target);",
+    //7: Pages we couldn't crawl, so later runs can leave them be for a while
+    "CREATE TABLE IF NOT EXISTS failed (url TEXT NOT NULL PRIMARY KEY, status INTEGER, error_kind TEXT NOT NULL, attempts INTEGER NOT NULL, last_attempt INTEGER NOT NULL);",
+    //8: Gzipped page bodies (with --keep-raw), so pages can be parsed again without fetching them again
+    "CREATE TABLE IF NOT EXISTS raw_page (url TEXT NOT NULL, content_hash TEXT NOT NULL, body BLOB NOT NULL, PRIMARY KEY (url, content_hash));",
+    //9: Pages without a body used to get an empty hash - they have no hash at all
+    "UPDATE site SET content_hash = NULL WHERE content_hash = '';"
+];
+
+///Opens (creating if need be) the database at db_path and brings its schema up to date
+pub fn open_db(db_path: &PathBuf) -> Result<Connection> {
+    let mut conn = Connection::open(db_path)?;
+    migrate(&mut conn)?;
+    Ok(conn)
+}
+
+///Brings an existing database's schema up to date, returning the schema versions it went from and to
+pub fn upgrade_db(db_path: &PathBuf) -> Result<(usize, usize)> {
+    if !db_path.exists() { return Err(Error::msg(format!("No database at {}", db_path.display()))) }
+    let mut conn = Connection::open(db_path)?;
+    let before = schema_version(&conn)?;
+    Ok((before, migrate(&mut conn)?))
+}
+
+pub fn schema_version(conn: &Connection) -> Result<usize> {
+    let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+///Applies any migrations the database hasn't had yet, returning the schema version it ends up at
+pub fn migrate(conn: &mut Connection) -> Result<usize> {
+    let version = schema_version(conn)?;
+    if version > MIGRATIONS.len() {
+        return Err(Error::msg(format!("Database is at schema version {version}, but this crawler only knows up to version {}", MIGRATIONS.len())))
+    }
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let cursor = conn.transaction()?;
+        for statement in migration.split(';').map(str::trim).filter(|statement| !statement.is_empty()) {
+            match cursor.execute_batch(statement) {
+                //The search app's db.create_all() makes its columns without bumping user_version, so they may already be there
+                Err(e) if statement.contains("ADD COLUMN") && e.to_string().contains("duplicate column name") => {},
+                res => res?
+            }
+        }
+        cursor.pragma_update(None, "user_version", (idx + 1) as i64)?;
+        cursor.commit()?;
+    }
+    Ok(MIGRATIONS.len())
+}
+
+///Crawl results kept in a SQLite database - the one the search app reads
+pub struct SqliteStore {
+    conn: Connection
+}
+impl SqliteStore {
+    pub fn open(db_path: &PathBuf) -> Result<SqliteStore> {
+        let conn = open_db(db_path)?;
+        //WAL lets the search app keep reading while we write
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        Ok(SqliteStore { conn })
+    }
+    ///For reworking what's already there - it can be written to, but like open_read_only nothing is migrated or switched over
+    pub fn open_current(db_path: &PathBuf) -> Result<SqliteStore> {
+        SqliteStore::open_unmigrated(db_path, OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI)
+    }
+    ///For just looking - nothing is migrated or written (not even the journal mode), and a database that's behind is an error
+    pub fn open_read_only(db_path: &PathBuf) -> Result<SqliteStore> {
+        SqliteStore::open_unmigrated(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI)
+    }
+    fn open_unmigrated(db_path: &PathBuf, flags: OpenFlags) -> Result<SqliteStore> {
+        if !db_path.exists() { return Err(Error::msg(format!("No database at {}", db_path.display()))) }
+        let conn = Connection::open_with_flags(db_path, flags)?;
+        let version = schema_version(&conn)?;
+        if version < MIGRATIONS.len() {
+            return Err(Error::msg(format!("Database is at schema version {version}, but this crawler needs version {} - run `db migrate` to bring it up to date", MIGRATIONS.len())))
+        }
+        if version > MIGRATIONS.len() {
+            return Err(Error::msg(format!("Database is at schema version {version}, but this crawler only knows up to version {}", MIGRATIONS.len())))
+        }
+        Ok(SqliteStore { conn })
+    }
+}
+
+///A stored page body, along with what we need to parse it again
+pub struct RawPage {
+    pub url: CanonicalUrl,
+    pub final_url: CanonicalUrl,
+    pub content_type: Option<String>,
+    pub body: Vec<u8> //Unzipped
+}
+impl SqliteStore {
+    ///Up to `limit` pages (in rowid order, starting after `after`) whose current body is stored.
+    ///Also returns the last rowid looked at, to carry on from - there's nothing left once the pages come back empty.
+    pub fn raw_pages(&self, after: i64, limit: usize) -> Result<(Vec<RawPage>, i64)> {
+        let mut raw_data = self.conn.prepare("SELECT site.rowid, site.url, site.final_url, site.content_type, raw_page.body FROM site
+            JOIN raw_page ON raw_page.url = site.url AND raw_page.content_hash = site.content_hash WHERE site.rowid > ?1 ORDER BY site.rowid LIMIT ?2")?;
+        let rows = raw_data.query_map(params![after, limit as i64], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?, row.get::<_, Option<String>>(3)?, row.get::<_, Vec<u8>>(4)?))
+        })?;
+        let (mut pages, mut last) = (Vec::new(), after);
+        for row in rows {
+            let (rowid, url, final_url, content_type, body) = row?;
+            last = rowid;
+            let Some(url) = CanonicalUrl::from_stored(&url) else { continue };
+            let final_url = final_url.as_deref().and_then(CanonicalUrl::from_stored).unwrap_or_else(|| url.clone());
+            pages.push(RawPage { url, final_url, content_type, body: unpack(&body)? });
+        }
+        Ok((pages, last))
+    }
+    ///Swaps in freshly parsed titles, text and links, leaving everything we learnt from the fetch itself alone
+    pub fn update_parsed(&mut self, pages: &[(CanonicalUrl, ParsedPage)]) -> Result<()> {
+        let cursor = self.conn.transaction()?;
+        for (url, parsed) in pages {
+            cursor.execute("UPDATE site SET title = ?2, body_text = ?3 WHERE url = ?1", params![url.as_str(), parsed.data.title, parsed.data.body_text])?;
+            write_links(&cursor, url, &parsed.links)?;
+        }
+        cursor.commit()?;
+        Ok(())
     }
-    Ok(())
 }
+///A page's url, title and text, for searching through
+pub type SearchCandidate = (String, Option<String>, Option<String>);
 
-pub fn update_db(db_path: &PathBuf, site_map: Arc<SiteMap>) {
-    match Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_WRITE) {
-        Ok(mut conn) => {
-            let cursor = conn.transaction().expect("Unable to create transaction");
-            let map = site_map.get_map();
-            for (url, data) in &*map {
-                match cursor.execute("INSERT INTO site VALUES (?1, ?2)", params![url, data.title]) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        //cursor.rollback().unwrap();
-                        return eprintln!("DATABASE ERROR, continuing: {e}")
-                    }
-                }
+///A rough picture of what's in a database
+#[derive(Debug)]
+pub struct DbStats {
+    pub version: usize,
+    pub pages: usize,
+    pub statuses: Vec<(Option<u16>, usize)>, //Most common first
+    pub hosts: Vec<(String, usize)>, //Most pages first
+    pub host_count: usize,
+    pub failures: Vec<(String, usize)>, //By kind of error, most common first
+    pub links: usize,
+    pub aliases: usize,
+    pub frontier: usize,
+    pub raw_pages: usize,
+    pub last_crawled: Option<i64>
+}
+impl SqliteStore {
+    ///Counts up pages, hosts, failures and the rest, listing the `top` busiest hosts
+    pub fn stats(&self, top: usize) -> Result<DbStats> {
+        let count = |table: &str| -> Result<usize> { Ok(self.conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| row.get::<_, i64>(0))? as usize) };
+        let mut raw_statuses = self.conn.prepare("SELECT status, COUNT(*) AS pages FROM site GROUP BY status ORDER BY pages DESC")?;
+        let statuses = raw_statuses.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as usize)))?.collect::<rusqlite::Result<_>>()?;
+        let mut raw_failures = self.conn.prepare("SELECT error_kind, COUNT(*) AS pages FROM failed GROUP BY error_kind ORDER BY pages DESC")?;
+        let failures = raw_failures.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as usize)))?.collect::<rusqlite::Result<_>>()?;
+        //Hosts aren't a column, so they're counted up here
+        let mut host_pages: HashMap<String, usize> = HashMap::new();
+        let mut raw_urls = self.conn.prepare("SELECT url FROM site")?;
+        for url in raw_urls.query_map([], |row| row.get::<_, String>(0))? {
+            if let Some(url) = CanonicalUrl::from_stored(&url?) { *host_pages.entry(url.host().to_string()).or_default() += 1; }
+        }
+        let host_count = host_pages.len();
+        let mut hosts: Vec<(String, usize)> = host_pages.into_iter().collect();
+        hosts.sort_by(|(host_a, pages_a), (host_b, pages_b)| pages_b.cmp(pages_a).then_with(|| host_a.cmp(host_b)));
+        hosts.truncate(top);
+        Ok(DbStats {
+            version: schema_version(&self.conn)?, pages: count("site")?, statuses, hosts, host_count, failures,
+            links: count("link")?, aliases: count("alias")?, frontier: count("frontier")?, raw_pages: count("raw_page")?,
+            last_crawled: self.conn.query_row("SELECT MAX(last_crawled) FROM site", [], |row| row.get(0))?
+        })
+    }
+    ///Pages that mention a term anywhere in their url, title or text (ignoring case) - the rough cut before whole-word matching
+    pub fn search_candidates(&self, term: &str) -> Result<Vec<SearchCandidate>> {
+        let like = format!("%{}%", term.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        let mut raw_data = self.conn.prepare("SELECT url, title, body_text FROM site WHERE title LIKE ?1 ESCAPE '\\' OR url LIKE ?1 ESCAPE '\\' OR body_text LIKE ?1 ESCAPE '\\'")?;
+        let rows = raw_data.query_map([like], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+    ///Hands every row of a table to `on_row`, along with the table's column names
+    pub fn dump_table(&self, table: &str, mut on_row: impl FnMut(&[String], Vec<rusqlite::types::Value>) -> Result<()>) -> Result<()> {
+        let mut raw_data = self.conn.prepare(&format!("SELECT * FROM {table}"))?;
+        let columns: Vec<String> = raw_data.column_names().into_iter().map(String::from).collect();
+        let mut rows = raw_data.query([])?;
+        while let Some(row) = rows.next()? {
+            let values = (0..columns.len()).map(|idx| row.get(idx)).collect::<rusqlite::Result<_>>()?;
+            on_row(&columns, values)?;
+        }
+        Ok(())
+    }
+}
+impl CrawlStore for SqliteStore {
+    fn load_seen(&mut self, cooldown: Duration) -> Result<Vec<CanonicalUrl>> {
+        let mut seen = Vec::new();
+        let mut raw_data = self.conn.prepare("SELECT url, title FROM site")?;
+        let tuple_data = raw_data.query_map([], |row| {
+            let url: String = row.get(0)?;
+            //let title: Option<String> = row.get(1)?;
+            Ok(url)
+        })?;
+        for row in tuple_data {
+            if let Some(url) = CanonicalUrl::from_stored(&row?) { seen.push(url); }
+        }
+        let mut raw_aliases = self.conn.prepare("SELECT url FROM alias")?;
+        let aliases = raw_aliases.query_map([], |row| row.get::<_, String>(0))?;
+        for alias in aliases {
+            if let Some(url) = CanonicalUrl::from_stored(&alias?) { seen.push(url); }
+        }
+        let mut raw_failed = self.conn.prepare("SELECT url FROM failed WHERE last_attempt > ?1")?;
+        let failed = raw_failed.query_map([unix_now() - cooldown.as_secs() as i64], |row| row.get::<_, String>(0))?;
+        for failure in failed {
+            if let Some(url) = CanonicalUrl::from_stored(&failure?) { seen.push(url); }
+        }
+        Ok(seen)
+    }
+    fn load_frontier(&mut self) -> Result<Vec<FrontierEntry>> {
+        let mut raw_data = self.conn.prepare("SELECT url, depth, referrer FROM frontier")?;
+        let tuple_data = raw_data.query_map([], |row| {
+            let url: String = row.get(0)?;
+            let depth: u32 = row.get(1)?;
+            let referrer: Option<String> = row.get(2)?;
+            Ok((url, depth, referrer))
+        })?;
+        let mut entries = Vec::new();
+        for row in tuple_data {
+            let (url, depth, referrer) = row?;
+            if let Some(url) = CanonicalUrl::from_stored(&url) {
+                entries.push(FrontierEntry { url, depth, referrer: referrer.and_then(|referrer| CanonicalUrl::from_stored(&referrer)), attempts: 0 });
             }
-            cursor.commit().unwrap();
-        },
-        Err(e) => eprintln!("DATABASE ERROR: {e}\nTLDR; The input database path probably didn't lead to a proper database")
+        }
+        Ok(entries)
+    }
+    fn write_pages(&mut self, pages: &[PageRecord], stats: &mut WriteStats) { write_batch(&mut self.conn, pages, stats) }
+    fn write_failures(&mut self, failures: &[FailureRecord]) -> Result<()> { Ok(write_failures(&mut self.conn, failures)?) }
+    fn save_frontier(&mut self, entries: &[FrontierEntry]) -> Result<()> { write_frontier(&mut self.conn, entries) }
+}
+
+///Upserts a batch of pages in one transaction. A row that fails is noted in stats and skipped, rather than sinking the whole batch.
+fn write_batch(conn: &mut Connection, batch: &[PageRecord], stats: &mut WriteStats) {
+    let fail_all = |stats: &mut WriteStats, e: rusqlite::Error| {
+        stats.failed.extend(batch.iter().map(|(url, _, _)| (url.clone(), e.to_string())));
+    };
+    let mut cursor = match conn.transaction() {
+        Ok(cursor) => cursor,
+        Err(e) => return fail_all(stats, e)
+    };
+    let now = unix_now();
+    let mut written = 0;
+    for (url, data, links) in batch {
+        //Each page gets its own savepoint, so a page that fails partway through is rolled back whole and the rest of the batch still goes in
+        let result = cursor.savepoint().and_then(|page_point| {
+            write_page(&page_point, now, url, data, links)?;
+            page_point.commit()
+        });
+        match result {
+            Ok(()) => written += 1,
+            Err(e) => stats.failed.push((url.clone(), e.to_string()))
+        }
+    }
+    match cursor.commit() {
+        Ok(()) => stats.written += written,
+        Err(e) => {
+            stats.failed.retain(|(failed_url, _)| !batch.iter().any(|(url, _, _)| url == failed_url));
+            fail_all(stats, e)
+        }
+    }
+}
+
+///Everything we keep about one page - its row, links, aliases and raw body
+fn write_page(cursor: &Connection, now: i64, url: &CanonicalUrl, data: &PageData, links: &[FoundLink]) -> rusqlite::Result<()> {
+    let row = params![url.as_str(), data.title, data.body_text, now, data.status, data.final_url, data.content_type, data.content_length as i64, data.fetched_at,
+        data.latency.as_millis() as i64, data.depth, data.referrer.as_ref().map(|referrer| referrer.as_str()), data.content_hash];
+    cursor.execute("INSERT INTO site (url, title, body_text, last_crawled, status, final_url, content_type, content_length, fetched_at, latency_ms, depth, referrer, content_hash)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+        ON CONFLICT(url) DO UPDATE SET title = excluded.title, body_text = excluded.body_text, last_crawled = excluded.last_crawled, status = excluded.status, final_url = excluded.final_url,
+            content_type = excluded.content_type, content_length = excluded.content_length, fetched_at = excluded.fetched_at, latency_ms = excluded.latency_ms,
+            depth = excluded.depth, referrer = excluded.referrer, content_hash = excluded.content_hash", row)?;
+    write_links(cursor, url, links)?;
+    write_aliases(cursor, url, data)?;
+    write_raw(cursor, url, data)?;
+    cursor.execute("DELETE FROM failed WHERE url = ?1", [url.as_str()])?;
+    Ok(())
+}
+
+///Replaces the outgoing edges of a page with the links we just found on it
+fn write_links(cursor: &Connection, source: &CanonicalUrl, links: &[FoundLink]) -> rusqlite::Result<()> {
+    cursor.execute("DELETE FROM link WHERE source = ?1", [source.as_str()])?;
+    let mut insert = cursor.prepare_cached("INSERT OR IGNORE INTO link (source, target, anchor, nofollow) VALUES (?1, ?2, ?3, ?4)")?;
+    for link in links {
+        insert.execute(params![source.as_str(), link.url.as_str(), link.anchor, link.nofollow])?;
+    }
+    Ok(())
+}
+
+///Notes down pages that failed, adding to the attempts of any that failed last time too
+fn write_failures(conn: &mut Connection, failures: &[FailureRecord]) -> rusqlite::Result<()> {
+    let cursor = conn.transaction()?;
+    let now = unix_now();
+    for (url, kind, attempts) in failures {
+        let status = match kind { ErrorKind::Status(code) => Some(*code), _ => None };
+        cursor.execute("INSERT INTO failed (url, status, error_kind, attempts, last_attempt) VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT(url) DO UPDATE SET status = excluded.status, error_kind = excluded.error_kind, attempts = failed.attempts + excluded.attempts,
+                last_attempt = excluded.last_attempt", params![url.as_str(), status, kind.as_str(), attempts, now])?;
+    }
+    cursor.commit()
+}
+
+///Points every url that led to a page (its redirect chain, then where that ended up) at the key it's stored under
+fn write_aliases(cursor: &Connection, key: &CanonicalUrl, data: &PageData) -> rusqlite::Result<()> {
+    cursor.execute("DELETE FROM alias WHERE target = ?1", [key.as_str()])?;
+    let mut insert = cursor.prepare_cached("INSERT OR REPLACE INTO alias (url, target, hop) VALUES (?1, ?2, ?3)")?;
+    let hops = data.redirect_chain.iter().map(|url| url.as_str()).chain([data.final_url.as_str()]);
+    for (hop, url) in hops.enumerate().filter(|(_, url)| !url.is_empty() && *url != key.as_str()) {
+        insert.execute(params![url, key.as_str(), hop])?;
+    }
+    Ok(())
+}
+
+///Keeps a gzipped copy of the page body, if we were asked to and it isn't already there
+fn write_raw(cursor: &Connection, url: &CanonicalUrl, data: &PageData) -> rusqlite::Result<()> {
+    let (Some(body), Some(hash)) = (&data.raw_body, &data.content_hash) else { return Ok(()) };
+    let packed = pack(body).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    cursor.execute("INSERT OR IGNORE INTO raw_page (url, content_hash, body) VALUES (?1, ?2, ?3)", params![url.as_str(), hash, packed])?;
+    Ok(())
+}
+
+fn pack(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(body)?;
+    gz.finish()
+}
+
+fn unpack(packed: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    GzDecoder::new(packed).read_to_end(&mut body)?;
+    Ok(body)
+}
+
+fn write_frontier(conn: &mut Connection, entries: &[FrontierEntry]) -> Result<()> {
+    let cursor = conn.transaction()?;
+    cursor.execute("DELETE FROM frontier", [])?;
+    {
+        let mut insert = cursor.prepare("INSERT OR IGNORE INTO frontier VALUES (?1, ?2, ?3)")?;
+        for entry in entries {
+            insert.execute(params![entry.url.as_str(), entry.depth, entry.referrer.as_ref().map(|referrer| referrer.as_str())])?;
+        }
+    }
+    cursor.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonical_url::url;
+    ///A fresh database with the whole schema
+    fn fresh_db() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn
     }
-}
\ No newline at end of file
+    fn tables(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name").unwrap();
+        stmt.query_map([], |row| row.get(0)).unwrap().map(|name| name.unwrap()).collect()
+    }
+    fn version(conn: &Connection) -> i64 { conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap() }
+    //migrate Tests
+    #[test]
+    fn migrate_fresh() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate(&mut conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+        assert!(tables(&conn).contains(&"site".to_string()));
+        assert!(tables(&conn).contains(&"frontier".to_string()));
+    }
+    #[test]
+    fn migrate_search_app_db() {
+        //What the search app's db.create_all() makes
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE site (url VARCHAR(100) NOT NULL, title VARCHAR(50), PRIMARY KEY (url)); INSERT INTO site VALUES ('gwango.lol', 'Gwango');").unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+        let title: String = conn.query_row("SELECT title FROM site WHERE url = 'gwango.lol'", [], |row| row.get(0)).unwrap();
+        assert_eq!(title, "Gwango");
+    }
+    #[test]
+    fn migrate_search_app_db_with_text() {
+        //A newer search app makes the body_text column itself
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE site (url VARCHAR(100) NOT NULL, title VARCHAR(50), body_text TEXT, PRIMARY KEY (url));").unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+        let mut stats = WriteStats::default();
+        write_batch(&mut conn, &[page("https://gwango.lol/", "Gwango")], &mut stats);
+        assert_eq!(stats.written, 1);
+    }
+    #[test]
+    fn migrate_twice() {
+        let mut conn = fresh_db();
+        assert_eq!(migrate(&mut conn).unwrap(), MIGRATIONS.len());
+    }
+    //write_batch Tests
+    fn page(page_url: &str, title: &str) -> PageRecord {
+        (url(page_url), PageData { title: Some(title.to_string()), status: 200, ..PageData::default() }, Vec::new())
+    }
+    fn title_of(conn: &Connection, url: &str) -> Option<String> {
+        conn.query_row("SELECT title FROM site WHERE url = ?1", [url], |row| row.get(0)).ok()
+    }
+    #[test]
+    fn write_batch_upserts() {
+        let mut conn = fresh_db();
+        let mut stats = WriteStats::default();
+        write_batch(&mut conn, &[page("https://gwango.lol/", "Old")], &mut stats);
+        write_batch(&mut conn, &[page("https://gwango.lol/", "New"), page("https://google.com/", "Google")], &mut stats);
+        assert_eq!(stats.written, 3);
+        assert!(stats.failed.is_empty());
+        assert_eq!(title_of(&conn, "https://gwango.lol/"), Some("New".to_string()));
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM site WHERE last_crawled IS NOT NULL AND status = 200", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 2);
+        //Neither page came with a body, so neither has a hash
+        let hashed: i64 = conn.query_row("SELECT COUNT(*) FROM site WHERE content_hash IS NOT NULL", [], |row| row.get(0)).unwrap();
+        assert_eq!(hashed, 0);
+    }
+    #[test]
+    fn write_batch_links() {
+        let mut conn = fresh_db();
+        let link = |target: &str, anchor: Option<&str>| FoundLink { url: url(target), anchor: anchor.map(String::from), nofollow: false };
+        let mut home = page("https://gwango.lol/", "Home");
+        home.2 = vec![link("https://google.com/", Some("Google")), link("https://gwango.lol/about", None), link("https://google.com/", Some("Again"))];
+        let mut stats = WriteStats::default();
+        write_batch(&mut conn, &[home.clone()], &mut stats);
+        home.2.pop();
+        home.2.pop(); //Links dropped on a re-crawl go away
+        write_batch(&mut conn, &[home], &mut stats);
+        let mut stmt = conn.prepare("SELECT source, anchor FROM link WHERE target = 'https://google.com/'").unwrap();
+        let inlinks: Vec<(String, Option<String>)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().map(|row| row.unwrap()).collect();
+        assert_eq!(inlinks, vec![("https://gwango.lol/".to_string(), Some("Google".to_string()))]);
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM link", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+    }
+    #[test]
+    fn write_batch_aliases() {
+        let mut conn = fresh_db();
+        let mut moved = page("https://www.gwango.lol/home", "Home");
+        moved.1.redirect_chain = vec![url("http://gwango.lol/"), url("https://gwango.lol/")];
+        moved.1.final_url = "https://www.gwango.lol/home?lang=en".to_string(); //Named /home as its canonical url
+        let mut stats = WriteStats::default();
+        write_batch(&mut conn, &[moved], &mut stats);
+        let mut stmt = conn.prepare("SELECT url, hop FROM alias WHERE target = 'https://www.gwango.lol/home' ORDER BY hop").unwrap();
+        let aliases: Vec<(String, i64)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().map(|row| row.unwrap()).collect();
+        assert_eq!(aliases, vec![("http://gwango.lol/".to_string(), 0), ("https://gwango.lol/".to_string(), 1), ("https://www.gwango.lol/home?lang=en".to_string(), 2)]);
+        drop(stmt);
+        //Nothing's recorded for a page we fetched right where we expected it
+        write_batch(&mut conn, &[page("https://google.com/", "Google")], &mut stats);
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM alias", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 3);
+    }
+    #[test]
+    fn write_failures_adds_up() {
+        let mut conn = fresh_db();
+        write_failures(&mut conn, &[(url("https://gwango.lol/flaky"), ErrorKind::Timeout, 3), (url("https://gwango.lol/gone"), ErrorKind::Status(404), 1)]).unwrap();
+        write_failures(&mut conn, &[(url("https://gwango.lol/flaky"), ErrorKind::Status(503), 3)]).unwrap();
+        let flaky: (Option<u16>, String, u32) = conn.query_row("SELECT status, error_kind, attempts FROM failed WHERE url = 'https://gwango.lol/flaky'", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+        assert_eq!(flaky, (Some(503), "status".to_string(), 6));
+        //Pages that work out after all aren't failures any more
+        let mut stats = WriteStats::default();
+        write_batch(&mut conn, &[page("https://gwango.lol/flaky", "Flaky")], &mut stats);
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM failed", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+    }
+    #[test]
+    fn write_batch_keeps_going() {
+        let mut conn = fresh_db();
+        conn.execute_batch("CREATE TRIGGER no_bad BEFORE INSERT ON site WHEN NEW.url LIKE '%bad%' BEGIN SELECT RAISE(ABORT, 'no bad pages'); END;").unwrap();
+        let mut stats = WriteStats::default();
+        write_batch(&mut conn, &[page("https://gwango.lol/", "Good"), page("https://bad.lol/", "Bad"), page("https://google.com/", "Also Good")], &mut stats);
+        assert_eq!(stats.written, 2);
+        assert_eq!(stats.failed.len(), 1);
+        assert_eq!(stats.failed[0].0.as_str(), "https://bad.lol/");
+        assert_eq!(title_of(&conn, "https://google.com/"), Some("Also Good".to_string()));
+    }
+    #[test]
+    fn write_batch_rolls_back_half_pages() {
+        let mut conn = fresh_db();
+        conn.execute_batch("CREATE TRIGGER no_bad_links BEFORE INSERT ON link WHEN NEW.target LIKE '%bad%' BEGIN SELECT RAISE(ABORT, 'no bad links'); END;").unwrap();
+        let mut linker = page("https://gwango.lol/", "Linker");
+        linker.2 = vec![FoundLink { url: url("https://bad.lol/"), anchor: None, nofollow: false }];
+        let mut stats = WriteStats::default();
+        write_batch(&mut conn, &[linker, page("https://google.com/", "Google")], &mut stats);
+        assert_eq!(stats.written, 1);
+        assert_eq!(stats.failed[0].0.as_str(), "https://gwango.lol/");
+        //The site row went in before the link failed, but it's gone along with the rest of the page
+        assert_eq!(title_of(&conn, "https://gwango.lol/"), None);
+        assert_eq!(title_of(&conn, "https://google.com/"), Some("Google".to_string()));
+    }
+    //Raw page Tests
+    #[test]
+    fn raw_pages_round_trip() {
+        let mut store = SqliteStore { conn: fresh_db() };
+        let mut kept = page("https://gwango.lol/", "Old Title");
+        kept.1.final_url = "https://gwango.lol/index.html".to_string();
+        kept.1.content_type = Some("text/html".to_string());
+        kept.1.content_hash = Some("abc".to_string());
+        kept.1.raw_body = Some(b"<title>New Title</title><a href='about'>About</a>".to_vec());
+        let mut stats = WriteStats::default();
+        //The second page was crawled without --keep-raw, so there's nothing to parse again
+        write_batch(&mut store.conn, &[kept.clone(), page("https://google.com/", "Google")], &mut stats);
+        write_batch(&mut store.conn, &[kept], &mut stats);
+        let count: i64 = store.conn.query_row("SELECT COUNT(*) FROM raw_page", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+        let (pages, last) = store.raw_pages(0, 10).unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].final_url.as_str(), "https://gwango.lol/index.html");
+        assert_eq!(pages[0].body, b"<title>New Title</title><a href='about'>About</a>");
+        assert!(store.raw_pages(last, 10).unwrap().0.is_empty());
+        //Parsing it again only touches the parsed bits
+        let link = FoundLink { url: url("https://gwango.lol/about"), anchor: Some("About".to_string()), nofollow: false };
+        let reparsed = ParsedPage { data: PageData { title: Some("New Title".to_string()), ..PageData::default() }, links: vec![link], ..ParsedPage::default() };
+        store.update_parsed(&[(pages[0].url.clone(), reparsed)]).unwrap();
+        assert_eq!(title_of(&store.conn, "https://gwango.lol/"), Some("New Title".to_string()));
+        let (status, target): (u16, String) = store.conn.query_row("SELECT status, target FROM site JOIN link ON link.source = site.url", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!((status, target.as_str()), (200, "https://gwango.lol/about"));
+    }
+    //Stats/search Tests
+    #[test]
+    fn stats_add_up() {
+        let mut store = SqliteStore { conn: fresh_db() };
+        let mut stats = WriteStats::default();
+        let mut missing = page("https://gwango.lol/missing", "Missing");
+        missing.1.status = 404;
+        write_batch(&mut store.conn, &[page("https://gwango.lol/", "Home"), page("https://gwango.lol/about", "About"), page("https://google.com/", "Google"), missing], &mut stats);
+        write_failures(&mut store.conn, &[(url("https://gwango.lol/flaky"), ErrorKind::Timeout, 3)]).unwrap();
+        let db_stats = store.stats(1).unwrap();
+        assert_eq!((db_stats.version, db_stats.pages, db_stats.host_count), (MIGRATIONS.len(), 4, 2));
+        assert_eq!(db_stats.statuses, vec![(Some(200), 3), (Some(404), 1)]);
+        assert_eq!(db_stats.hosts, vec![("gwango.lol".to_string(), 3)]);
+        assert_eq!(db_stats.failures, vec![("timeout".to_string(), 1)]);
+        assert!(db_stats.last_crawled.is_some());
+    }
+    #[test]
+    fn search_candidates_escape() {
+        let mut store = SqliteStore { conn: fresh_db() };
+        let mut stats = WriteStats::default();
+        let mut crab = page("https://gwango.lol/crabs", "All About Crabs");
+        crab.1.body_text = Some("100% crab, no filler".to_string());
+        write_batch(&mut store.conn, &[crab, page("https://google.com/", "Google")], &mut stats);
+        assert_eq!(store.search_candidates("CRAB").unwrap().len(), 1);
+        assert_eq!(store.search_candidates("100%").unwrap().len(), 1);
+        assert!(store.search_candidates("_oogle").unwrap().is_empty());
+        let mut rows = Vec::new();
+        store.dump_table("site", |columns, values| { rows.push((columns[0].clone(), values[0].clone())); Ok(()) }).unwrap();
+        assert_eq!(rows[0], ("url".to_string(), rusqlite::types::Value::Text("https://gwango.lol/crabs".to_string())));
+    }
+    #[test]
+    fn read_only_needs_migrating() {
+        let path = std::env::temp_dir().join(format!("web_crawler_test_{}_read_only.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Connection::open(&path).unwrap().execute_batch(MIGRATIONS[0]).unwrap();
+        let e = SqliteStore::open_read_only(&path).err().unwrap();
+        assert!(e.to_string().contains("db migrate"));
+        //Looking didn't change anything
+        assert_eq!(version(&Connection::open(&path).unwrap()), 0);
+        upgrade_db(&path).unwrap();
+        let store = SqliteStore::open_read_only(&path).unwrap();
+        assert_eq!(store.stats(5).unwrap().pages, 0);
+        assert!(store.conn.execute("DELETE FROM site", []).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+    #[test]
+    fn open_current_needs_migrating() {
+        let path = std::env::temp_dir().join(format!("web_crawler_test_{}_open_current.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Connection::open(&path).unwrap().execute_batch(MIGRATIONS[0]).unwrap();
+        let e = SqliteStore::open_current(&path).err().unwrap();
+        assert!(e.to_string().contains("db migrate"));
+        assert_eq!(version(&Connection::open(&path).unwrap()), 0);
+        upgrade_db(&path).unwrap();
+        //Writable, but left in whatever journal mode it was in
+        let store = SqliteStore::open_current(&path).unwrap();
+        assert!(store.conn.execute("DELETE FROM site", []).is_ok());
+        assert_eq!(store.conn.pragma_query_value(None, "journal_mode", |row| row.get::<_, String>(0)).unwrap(), "delete");
+        let _ = std::fs::remove_file(&path);
+    }
+    #[test]
+    fn migrate_from_the_future() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() as i64 + 1).unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::prelude::*;
+use crate::crawler_datatypes::FrontierEntry;
+use crate::canonical_url::CanonicalUrl;
+use crate::crawl_store::{CrawlStore, PageRecord, FailureRecord, WriteStats, unix_now};
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+///Crawl results appended to a JSON Lines file, one object per line, for pipelines that would rather not touch SQLite.
+///Pages are `{"kind": "page", ...}` lines (links included) and failures are `{"kind": "failed", ...}` lines. A page
+///crawled again just gets another line - the last one wins. The frontier lives next door in `<name>.frontier.jsonl`.
+pub struct JsonLinesStore {
+    path: PathBuf,
+    out: BufWriter<File>,
+    attempts: Option<HashMap<String, u32>> //Attempts so far at each url that's still failing, read in when first needed
+}
+impl JsonLinesStore {
+    pub fn open(path: &PathBuf) -> Result<JsonLinesStore> {
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        //A line cut short by a crash would swallow whatever we write next, so give it an end of its own
+        if file.metadata()?.len() > 0 {
+            let mut last = [0];
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+            if last != *b"\n" { file.write_all(b"\n")?; }
+        }
+        Ok(JsonLinesStore { path: path.clone(), out: BufWriter::new(file), attempts: None })
+    }
+    fn frontier_path(&self) -> PathBuf { self.path.with_extension("frontier.jsonl") }
+    ///Every line in a file we can make sense of - a line cut short by a crash is skipped rather than spoiling the rest
+    fn read_lines(path: &PathBuf) -> Result<Vec<Value>> {
+        if !path.exists() { return Ok(Vec::new()) }
+        let mut lines = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            if let Ok(value) = serde_json::from_str::<Value>(&line?) { lines.push(value); }
+        }
+        Ok(lines)
+    }
+    ///Attempts so far at each url that's still failing, going by its latest failed line (a page line since means it worked out)
+    fn load_attempts(&self) -> Result<HashMap<String, u32>> {
+        let mut attempts = HashMap::new();
+        for line in JsonLinesStore::read_lines(&self.path)? {
+            let Some(url) = line["url"].as_str() else { continue };
+            match line["kind"].as_str() {
+                Some("failed") => { attempts.insert(url.to_string(), line["attempts"].as_u64().unwrap_or(0) as u32); },
+                Some("page") => { attempts.remove(url); },
+                _ => {}
+            }
+        }
+        Ok(attempts)
+    }
+    fn append(&mut self, line: Value) -> Result<()> {
+        serde_json::to_writer(&mut self.out, &line)?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+}
+impl CrawlStore for JsonLinesStore {
+    fn load_seen(&mut self, cooldown: Duration) -> Result<Vec<CanonicalUrl>> {
+        let cutoff = unix_now() - cooldown.as_secs() as i64;
+        let mut seen = Vec::new();
+        for line in JsonLinesStore::read_lines(&self.path)? {
+            let urls: Vec<&Value> = match line["kind"].as_str() {
+                Some("page") => [&line["url"], &line["final_url"]].into_iter().chain(line["redirect_chain"].as_array().into_iter().flatten()).collect(),
+                Some("failed") if line["last_attempt"].as_i64().is_some_and(|last| last > cutoff) => vec![&line["url"]],
+                _ => Vec::new()
+            };
+            seen.extend(urls.into_iter().filter_map(|url| url.as_str()).filter_map(CanonicalUrl::from_stored));
+        }
+        Ok(seen)
+    }
+    fn load_frontier(&mut self) -> Result<Vec<FrontierEntry>> {
+        let entries = JsonLinesStore::read_lines(&self.frontier_path())?.into_iter().filter_map(|line| Some(FrontierEntry {
+            url: CanonicalUrl::from_stored(line["url"].as_str()?)?,
+            depth: line["depth"].as_u64()? as u32,
+            referrer: line["referrer"].as_str().and_then(CanonicalUrl::from_stored),
+            attempts: 0
+        }));
+        Ok(entries.collect())
+    }
+    fn write_pages(&mut self, pages: &[PageRecord], stats: &mut WriteStats) {
+        let now = unix_now();
+        let mut written = 0;
+        for (url, data, links) in pages {
+            let links: Vec<Value> = links.iter().map(|link| json!({ "url": link.url.as_str(), "anchor": link.anchor, "nofollow": link.nofollow })).collect();
+            let line = json!({
+                "kind": "page", "url": url.as_str(), "title": data.title, "body_text": data.body_text, "last_crawled": now, "status": data.status,
+                "final_url": data.final_url, "redirect_chain": data.redirect_chain.iter().map(|hop| hop.as_str()).collect::<Vec<&str>>(),
+                "content_type": data.content_type, "content_length": data.content_length, "fetched_at": data.fetched_at, "latency_ms": data.latency.as_millis() as u64,
+                "depth": data.depth, "referrer": data.referrer.as_ref().map(|referrer| referrer.as_str()), "content_hash": data.content_hash, "links": links
+            });
+            match self.append(line) {
+                Ok(()) => {
+                    written += 1;
+                    if let Some(attempts) = &mut self.attempts { attempts.remove(url.as_str()); }
+                },
+                Err(e) => stats.failed.push((url.clone(), e.to_string()))
+            }
+        }
+        match self.out.flush() {
+            Ok(()) => stats.written += written,
+            Err(e) => stats.failed.extend(pages.iter().map(|(url, _, _)| (url.clone(), e.to_string())))
+        }
+    }
+    fn write_failures(&mut self, failures: &[FailureRecord]) -> Result<()> {
+        let now = unix_now();
+        if self.attempts.is_none() { self.attempts = Some(self.load_attempts()?); }
+        for (url, kind, attempts) in failures {
+            let status = match kind { crate::crawler_datatypes::ErrorKind::Status(code) => Some(*code), _ => None };
+            //Attempts add up across runs, like they do in SQLite
+            let total = self.attempts.as_ref().and_then(|so_far| so_far.get(url.as_str())).copied().unwrap_or(0) + attempts;
+            self.append(json!({ "kind": "failed", "url": url.as_str(), "status": status, "error_kind": kind.as_str(), "attempts": total, "last_attempt": now }))?;
+            if let Some(so_far) = &mut self.attempts { so_far.insert(url.to_string(), total); }
+        }
+        Ok(self.out.flush()?)
+    }
+    fn save_frontier(&mut self, entries: &[FrontierEntry]) -> Result<()> {
+        //Written to the side then moved into place, so a crash can't leave half a frontier
+        let frontier_path = self.frontier_path();
+        let temp_path = frontier_path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&temp_path)?);
+        for entry in entries {
+            serde_json::to_writer(&mut out, &json!({ "url": entry.url.as_str(), "depth": entry.depth, "referrer": entry.referrer.as_ref().map(|referrer| referrer.as_str()) }))?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        fs::rename(temp_path, frontier_path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crawler_datatypes::{PageData, FoundLink, ErrorKind};
+    use crate::canonical_url::url;
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("web_crawler_test_{}_{name}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(path.with_extension("frontier.jsonl"));
+        path
+    }
+    //JsonLinesStore Tests
+    #[test]
+    fn jsonl_round_trip() {
+        let path = temp_path("round_trip");
+        let mut store = JsonLinesStore::open(&path).unwrap();
+        let data = PageData { title: Some("Home".to_string()), final_url: "https://www.gwango.lol/".to_string(), redirect_chain: vec![url("http://gwango.lol/")], ..PageData::default() };
+        let links = vec![FoundLink { url: url("https://google.com/"), anchor: Some("Google".to_string()), nofollow: true }];
+        let mut stats = WriteStats::default();
+        store.write_pages(&[(url("https://www.gwango.lol/"), data, links)], &mut stats);
+        store.write_failures(&[(url("https://gwango.lol/gone"), ErrorKind::Status(404), 1)]).unwrap();
+        store.save_frontier(&[FrontierEntry::start(url("https://gwango.lol/next"))]).unwrap();
+        assert_eq!(stats.written, 1);
+        //A fresh store on the same file sees it all
+        let mut store = JsonLinesStore::open(&path).unwrap();
+        let seen = store.load_seen(Duration::from_secs(3600)).unwrap();
+        assert_eq!(seen, vec![url("https://www.gwango.lol/"), url("https://www.gwango.lol/"), url("http://gwango.lol/"), url("https://gwango.lol/gone")]);
+        assert_eq!(store.load_frontier().unwrap(), vec![FrontierEntry::start(url("https://gwango.lol/next"))]);
+        let first: Value = serde_json::from_str(fs::read_to_string(&path).unwrap().lines().next().unwrap()).unwrap();
+        assert_eq!(first["links"][0], json!({ "url": "https://google.com/", "anchor": "Google", "nofollow": true }));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(path.with_extension("frontier.jsonl"));
+    }
+    #[test]
+    fn jsonl_failures_add_up() {
+        let path = temp_path("failures");
+        let mut store = JsonLinesStore::open(&path).unwrap();
+        store.write_failures(&[(url("https://gwango.lol/flaky"), ErrorKind::Timeout, 3), (url("https://gwango.lol/fixed"), ErrorKind::Timeout, 1)]).unwrap();
+        store.write_pages(&[(url("https://gwango.lol/fixed"), PageData::default(), Vec::new())], &mut WriteStats::default());
+        //A later run carries on counting (and starts over for pages that worked out in between)
+        let mut store = JsonLinesStore::open(&path).unwrap();
+        store.write_failures(&[(url("https://gwango.lol/flaky"), ErrorKind::Status(503), 3), (url("https://gwango.lol/fixed"), ErrorKind::Timeout, 1)]).unwrap();
+        let last: Vec<Value> = fs::read_to_string(&path).unwrap().lines().rev().take(2).map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!((last[1]["url"].as_str(), last[1]["attempts"].as_u64()), (Some("https://gwango.lol/flaky"), Some(6)));
+        assert_eq!((last[0]["url"].as_str(), last[0]["attempts"].as_u64()), (Some("https://gwango.lol/fixed"), Some(1)));
+        let _ = fs::remove_file(&path);
+    }
+    #[test]
+    fn jsonl_skips_bad_lines() {
+        let path = temp_path("bad_lines");
+        fs::write(&path, "{\"kind\": \"page\", \"url\": \"https://gwango.lol/\"}\n{\"kind\": \"page\", \"url\": \"https://gwa").unwrap();
+        let mut store = JsonLinesStore::open(&path).unwrap();
+        assert_eq!(store.load_seen(Duration::ZERO).unwrap(), vec![url("https://gwango.lol/")]);
+        assert!(store.load_frontier().unwrap().is_empty());
+        //What we write next still gets a line to itself
+        store.write_failures(&[(url("https://gwango.lol/gone"), ErrorKind::Status(404), 1)]).unwrap();
+        assert_eq!(store.load_seen(Duration::from_secs(3600)).unwrap(), vec![url("https://gwango.lol/"), url("https://gwango.lol/gone")]);
+        let _ = fs::remove_file(&path);
+    }
+}
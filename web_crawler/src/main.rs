@@ -3,113 +3,414 @@ mod crawler_datatypes;
 mod crawler_utilities;
 mod database_interaction;
 mod url_tree;
+mod robots;
+mod politeness;
+mod canonical_url;
+mod page_encoding;
+mod crawl_store;
+mod jsonl_store;
+mod warc;
+mod commands;
 
 use crate::prelude::*;
 use crate::crawler_datatypes::*;
 use crate::crawler_utilities::*;
-use crate::database_interaction::*;
+use crate::crawl_store::*;
+use crate::commands::*;
+use crate::robots::{RobotsCache, ROBOTS_TOKEN};
+use crate::politeness::{HostScheduler, HostPermit};
+use crate::canonical_url::{CanonicalUrl, QueryFilter, DEFAULT_STRIPPED_PARAMS};
+use crate::page_encoding::decode_page;
+use crate::warc::{WarcWriter, WarcMsg, Exchange, replay, DEFAULT_WARC_MAX_SIZE};
 use clap::Parser;
-use reqwest::{blocking, StatusCode};
+use std::sync::mpsc::Sender;
+use reqwest::{Client, Response, StatusCode, header::{CONTENT_TYPE, LOCATION, RETRY_AFTER}, redirect::Policy};
 
 //TODO:
 //Fix scary deadlocking bug - DONE
 //Check if a url is a bad one that's already been searched - DONE
-//Add async for the web requesting
+//Add async for the web requesting - DONE
 //Random URL picking - DONE
 //Add sqlite database updating funcationality - DONE
 //Actually add this stuff to the github - DONE
 //Load database in at the beginning
 //Make extensibility feature
+//Respect robots.txt - DONE
+//Don't hammer hosts - DONE
+//Save results on Ctrl-C - DONE
+//Parse html properly instead of with regexes - DONE
+//Keep page text for searching - DONE
+//Work out page encodings ourselves - DONE
+//Respect noindex/nofollow - DONE
+//Don't download pdfs and such - DONE
+//Keep track of redirects - DONE
+//Retry pages that might work next time - DONE
+//Remember bad pages between runs - DONE
+//Pluggable storage - DONE
+//Archive raw pages as WARC - DONE
+//Reparse kept pages offline - DONE
+//Subcommands - DONE
+
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+///Most redirects we'll follow for one page, same as reqwest's default
+const MAX_REDIRECTS: usize = 10;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     /// Set the level of verbosity wanted
-    #[arg(short, long, default_value_t=1)]
+    #[arg(short, long, global=true, default_value_t=1)]
     log_level: u8,
+    /// Path to the database to use (when crawling, a .jsonl path writes JSON Lines instead of SQLite)
+    #[arg(short, long, global=true)]
+    db_path: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Crawl the web from the given URLs, saving the results into --db-path if given
+    Crawl(CrawlArgs),
+    /// Run the current parser over the page bodies kept by --keep-raw and update the database with the results (no network needed)
+    Reparse {
+        #[command(flatten)]
+        parsing: ParseArgs
+    },
+    /// Set up or upgrade a database
+    Db {
+        #[command(subcommand)]
+        action: DbAction
+    },
+    /// Summarize a database - pages, hosts, failures and so on
+    Stats {
+        /// Number of hosts to list
+        #[arg(long, default_value_t=10)]
+        top: usize
+    },
+    /// Dump a table of a database as JSON Lines or CSV
+    Export {
+        #[arg(value_enum, default_value_t=ExportTable::Site)]
+        table: ExportTable,
+        #[arg(short, long, value_enum, default_value_t=ExportFormat::Jsonl)]
+        format: ExportFormat,
+        /// File to write to [default: stdout]
+        #[arg(short, long)]
+        output: Option<PathBuf>
+    },
+    /// Search the crawled pages, the same way the web app does
+    Search {
+        /// Number of results to show
+        #[arg(short='n', long, default_value_t=10)]
+        limit: usize,
+        #[arg(required=true)]
+        query: Vec<String>
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct CrawlArgs {
     /// Panic on malformed inputs
     #[arg(long)]
     strict: bool,
     /// Number of links to crawl
     #[arg(short, long, default_value_t=100)]
     num: u32,
-    /// Number of workers used to crawl
+    /// Number of workers used to crawl (i.e. max number of requests in flight at once)
     #[arg(short, long, default_value_t=10)]
-    workers: u8,
-    /// Path to database to store results
-    #[arg(short, long)]
-    db_path: Option<PathBuf>,
+    workers: u16,
+    /// Commit crawled pages to the database every this many pages...
+    #[arg(long, default_value_t=100)]
+    batch_size: usize,
+    /// ...or every this many seconds, whichever comes first
+    #[arg(long, default_value_t=5)]
+    batch_secs: u64,
+    /// Crawl without checking robots.txt (only for sites you own!)
+    #[arg(long)]
+    ignore_robots: bool,
+    /// Ignore noindex/nofollow in meta robots tags, X-Robots-Tag headers and rel="nofollow" links (only for sites you own!)
+    #[arg(long)]
+    ignore_directives: bool,
+    /// Minimum milliseconds between requests to the same host (robots.txt Crawl-delay overrides this, up to a minute)
+    #[arg(long, default_value_t=500)]
+    host_delay: u64,
+    /// Max number of requests in flight to the same host at once
+    #[arg(long, default_value_t=2)]
+    max_per_host: usize,
+    /// Times to retry a page after a transient error (timeouts, dropped connections, 5xx, 429...)
+    #[arg(long, default_value_t=2)]
+    retries: u32,
+    /// Hours to leave pages that failed in earlier crawls into --db-path alone before trying them again
+    #[arg(long, default_value_t=24)]
+    failed_cooldown: u64,
+    /// MIME types to parse (a trailing /* matches a whole type) - anything else is recorded with its type but not downloaded
+    #[arg(long, value_name="MIME", default_values=DEFAULT_ACCEPTED_TYPES)]
+    accept_type: Vec<String>,
+    /// Max bytes of a response body to read - bigger pages are recorded but not parsed
+    #[arg(long, default_value_t=DEFAULT_MAX_BODY)]
+    max_body: u64,
+    /// Keep a gzipped copy of every page we parse in --db-path, so the `reparse` command can go over them again later
+    #[arg(long)]
+    keep_raw: bool,
+    /// Also write every request and response to WARC files (<WARC>-00000.warc.gz and so on), for archiving or reprocessing later
+    #[arg(long, value_name="WARC")]
+    warc: Option<PathBuf>,
+    /// Start a new WARC file once the current one is this many bytes
+    #[arg(long, default_value_t=DEFAULT_WARC_MAX_SIZE)]
+    warc_max_size: u64,
+    /// Instead of crawling, parse the pages archived in a WARC file (no network needed), saving them into --db-path if given
+    #[arg(long, value_name="WARC", conflicts_with_all=["warc", "resume"])]
+    replay: Option<PathBuf>,
+    /// Pick back up from the links the last crawl into --db-path didn't get to (on top of any starting URLs)
+    #[arg(long)]
+    resume: bool,
+    #[command(flatten)]
+    parsing: ParseArgs,
     /// List of starting URLs
     #[arg()]
     start_points: Vec<String>
 }
 
-fn main() {
-    //Parse arguments
-    let args = Args::parse();
+//How pages get parsed - shared by crawling and reparsing (not a doc comment, or clap makes it the app description)
+#[derive(clap::Args, Debug)]
+struct ParseArgs {
+    /// Max characters of each page's visible text to store for searching (0 stores none)
+    #[arg(long, default_value_t=DEFAULT_MAX_TEXT)]
+    max_text: usize,
+    /// Only keep query parameters matching these names in urls (a trailing * matches a prefix) [default: keep all]
+    #[arg(long, value_name="PARAM")]
+    keep_param: Vec<String>,
+    /// Strip query parameters matching these names from urls (a trailing * matches a prefix), on top of the usual tracking/session ones
+    #[arg(long, value_name="PARAM")]
+    strip_param: Vec<String>,
+}
+impl ParseArgs {
+    fn into_options(self) -> ParseOptions {
+        let stripped = DEFAULT_STRIPPED_PARAMS.iter().map(|param| param.to_string()).chain(self.strip_param).collect();
+        ParseOptions { filter: QueryFilter::new(self.keep_param, stripped), max_text: self.max_text }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    //Parse arguments and hand off to the command
+    let Args { log_level, db_path, command } = Args::parse();
+    let disp = make_disp(DispOptions::new(log_level, Arc::new(ProgressBar::hidden())));
+    //Crawls can go without a database, everything else works on one that's already there (or about to be)
+    match command {
+        Command::Crawl(args) => run_crawl(args, log_level, db_path).await,
+        Command::Reparse { parsing } => run_reparse(require_db(db_path.as_ref()), &parsing.into_options(), disp),
+        Command::Db { action } => run_db(action, require_db(db_path.as_ref()), disp),
+        Command::Stats { top } => run_stats(require_db(db_path.as_ref()), top),
+        Command::Export { table, format, output } => run_export(require_db(db_path.as_ref()), table, format, output.as_ref(), disp),
+        Command::Search { limit, query } => run_search(require_db(db_path.as_ref()), &query, limit)
+    }
+}
+
+///Hands back the database path, bailing out if there isn't one
+fn require_db(db_path: Option<&PathBuf>) -> &PathBuf {
+    db_path.unwrap_or_else(|| fail("This command needs a database - pass one with --db-path"))
+}
+
+async fn run_crawl(args: CrawlArgs, log_level: u8, db_path: Option<PathBuf>) {
+    let parse_opts = args.parsing.into_options();
     let mut start_points = Vec::new();
     for url in args.start_points {
-        match cleanse_url(&url) {
-            Some(clean_url) => start_points.push(clean_url),
+        match cleanse_url(&url, &parse_opts.filter) {
+            Some(clean_url) => start_points.push(FrontierEntry::start(clean_url)),
             None => if args.strict { panic!("Input URL {url} not well formed") }
         }
     }
-    if start_points.is_empty() { return eprintln!("No valid starting URLs supplied; exiting :("); }
+    //Open up the store if need be
+    //Checked here rather than by clap, which can't see --db-path when it comes before the command
+    if (args.resume || args.keep_raw) && db_path.is_none() { fail("--resume and --keep-raw need a database - pass one with --db-path"); }
+    if args.keep_raw && db_path.as_deref().is_some_and(is_jsonl) { fail("--keep-raw needs a SQLite database, not a .jsonl --db-path"); }
+    let mut store = match &db_path {
+        Some(db_path) => match open_store(db_path) {
+            Ok(store) => Some(store),
+            Err(e) => fail(format!("DATABASE ERROR: {e}\nTLDR; Couldn't open or set up the database - the path is probably wrong, or it's not a database this crawler made"))
+        },
+        None => None
+    };
+    if let Some(warc_path) = &args.replay {
+        return replay_warc(warc_path, &parse_opts, store, !args.ignore_directives, args.batch_size, make_disp(DispOptions::new(log_level, Arc::new(ProgressBar::hidden()))));
+    }
+    if args.resume {
+        match store.as_mut().unwrap().load_frontier() {
+            Ok(frontier) => start_points.extend(frontier),
+            Err(e) => fail(format!("DATABASE ERROR: {e}\nTLDR; Couldn't read the saved frontier from the database"))
+        }
+    }
+    let seen = match store.as_mut().map(|store| store.load_seen(Duration::from_secs(args.failed_cooldown.saturating_mul(3600)))) {
+        Some(Ok(seen)) => seen,
+        Some(Err(e)) => fail(format!("DATABASE ERROR: {e}\nTLDR; Couldn't read properly from database - it's probably not one this crawler made")),
+        None => Vec::new()
+    };
+    if start_points.is_empty() { fail("No valid starting URLs supplied (or saved frontier to resume from); exiting :("); }
     //Create all important objects
-    let pbar = Arc::new(if args.log_level > 0 { ProgressBar::new(args.num as u64) }
+    let pbar = Arc::new(if log_level > 0 { ProgressBar::new(args.num as u64) }
         else { ProgressBar::hidden() }); 
     pbar.set_style(ProgressStyle::default_bar().template("[{bar:40.green/red}] {pos}/{len} {eta} {msg}").unwrap().progress_chars("|>-"));
-    let options = DispOptions::new(args.log_level, pbar.clone());
+    let options = DispOptions::new(log_level, pbar.clone());
     let disp = make_disp(options.clone());
-    let site_map = Arc::new(SiteMap::new(args.num, pbar.clone()));
+    //Start up database writer and load what's already been crawled
+    let writer = store.map(|store| DbWriter::spawn(store, args.batch_size, Duration::from_secs(args.batch_secs)));
+    let site_map = Arc::new(SiteMap::new(args.num, pbar.clone(), writer.as_ref().map(|writer| writer.sender())));
     let public_links = Arc::new(LinkList::new(start_points, args.workers));
-    //Load database if need be
-    if let Some(db_path) = &args.db_path {
-        match load_db(db_path, site_map.clone()) {
-            Ok(()) => {},
-            Err(e) => return eprintln!("DATABASE ERROR: {e}\nTLDR; Couldn't read properly from database - it's either misconfigured or the path is incorrect")
-        }
-    }
+    for url in seen { site_map.insert_previously(url); }
+    let warc_writer = match &args.warc {
+        Some(warc_path) => match WarcWriter::spawn(warc_path, args.warc_max_size) {
+            Ok(warc_writer) => Some(warc_writer),
+            Err(e) => fail(format!("WARC ERROR: {e}\nTLDR; Couldn't create the WARC file - the path is probably wrong"))
+        },
+        None => None
+    };
     //Spawn crawlers
     let timer = Instant::now();
-    disp(format!("Let the crabby crawling begin!"), 1);
+    disp("Let the crabby crawling begin!".to_string(), 1);
+    let client_builder = || Client::builder().timeout(Duration::from_secs(3)).user_agent(USER_AGENT);
+    let shared = CrawlShared {
+        client: client_builder().redirect(Policy::none()).build().unwrap(), //We follow redirects ourselves, to keep track of them
+        site_map: site_map.clone(),
+        pub_links: public_links.clone(),
+        robots: if args.ignore_robots { None } else { Some(Arc::new(RobotsCache::new(client_builder().build().unwrap(), args.retries))) },
+        scheduler: Arc::new(HostScheduler::new(Duration::from_millis(args.host_delay), args.max_per_host)),
+        fetch_opts: Arc::new(FetchOptions { accepted_types: args.accept_type, max_body: args.max_body, keep_raw: args.keep_raw }),
+        parse_opts: Arc::new(parse_opts),
+        obey_directives: !args.ignore_directives,
+        max_retries: args.retries,
+        warc: warc_writer.as_ref().map(|warc_writer| warc_writer.sender()),
+        options: options.clone()
+    };
+    //On Ctrl-C (or a kill), stop handing out urls and let the in-flight requests finish up, so we still save what we've got
+    tokio::spawn({
+        let public_links = public_links.clone();
+        let disp = make_disp(options.clone());
+        async move {
+            shutdown_signal().await;
+            disp("Caught shutdown signal - finishing in-flight requests and saving results (Ctrl-C again to quit right away)".to_string(), 0);
+            public_links.finish();
+            shutdown_signal().await;
+            std::process::exit(130);
+        }
+    });
     let mut crawly_bois = Vec::new();
     for worker_id in 0..args.workers {
-        let site_map_clone = site_map.clone();
-        let pub_links_clone = public_links.clone();
-        let options_clone = options.clone();
-        let handle = thread::spawn(move || get_crawlin(worker_id, site_map_clone, pub_links_clone, options_clone));
+        let handle = tokio::spawn(get_crawlin(worker_id, shared.clone()));
         crawly_bois.push(handle);
     }
     //Wait for crawlers to terminate
-    let mut outstanding = 0;
+    let mut leftovers = Vec::new();
     let mut tot_request_time = Duration::new(0,0);
     let mut tot_work_time = Duration::new(0,0);
+    let (mut nofollow_pages, mut nofollow_links, mut retries) = (0, 0, 0);
     for crawly_boi in crawly_bois {
-        let wdata = crawly_boi.join().expect("Crawly Boi panicked :(");
-        outstanding += wdata.outstanding;
+        let wdata = match crawly_boi.await {
+            Ok(wdata) => wdata,
+            //Its links are lost, but everyone else's results are still worth keeping
+            Err(e) => { eprintln!("Crawly Boi panicked :( {e}"); continue }
+        };
+        leftovers.extend(wdata.outstanding);
         tot_request_time += wdata.req_time;
         tot_work_time += wdata.tot_time;
+        nofollow_pages += wdata.nofollow_pages;
+        nofollow_links += wdata.nofollow_links;
+        retries += wdata.retries;
     }
+    leftovers.extend(public_links.drain());
+    let outstanding = leftovers.len();
     let elapsed = timer.elapsed();
     pbar.finish();
-    disp(format!("Finished crawling!\nSites crawled: {}\nOutstanding links: {}\nTime Crawling: {:?}\nRequest Time: {:?}\nWork Time: {:?}", site_map.len(), outstanding, elapsed, tot_request_time, tot_work_time), 1);
-    //Add results to database, if specified
-    match &args.db_path {
-        Some(path) => update_db(path, site_map),
-        None => {} //Do nothing, we're done here 
+    if public_links.is_exhausted() { disp(format!("Frontier exhausted - ran out of links to crawl before reaching {} sites", args.num), 1); }
+    disp(format!("Finished crawling!\nSites crawled: {}\nBlocked by robots.txt: {}\nSkipped as noindex: {}\nNofollow pages: {}\nNofollow links skipped: {}\nFailed: {} ({} transient, {} permanent) after {} retries\nOutstanding links: {}\nTime Crawling: {:?}\nRequest Time: {:?}\nWork Time: {:?}",
+        site_map.len(), site_map.bad_count(FailReason::RobotsDisallowed), site_map.bad_count(FailReason::NoIndex), nofollow_pages, nofollow_links,
+        site_map.bad_count_where(|reason| matches!(reason, FailReason::CrawlError(_))), site_map.bad_count_where(|reason| matches!(reason, FailReason::CrawlError(kind) if kind.is_transient())),
+        site_map.bad_count_where(|reason| matches!(reason, FailReason::CrawlError(kind) if !kind.is_transient())), retries, outstanding, elapsed, tot_request_time, tot_work_time), 1);
+    //Flush the last of the results to the database, if specified
+    if let Some(writer) = writer {
+        leftovers.retain(|link| !site_map.contains_key(&link.url));
+        writer.save_frontier(leftovers);
+        let stats = writer.finish();
+        disp(format!("Saved {} sites to the database ({} failed)", stats.written, stats.failed.len()), 1);
+        for (url, e) in &stats.failed { disp(format!("DATABASE ERROR, couldn't save {url}: {e}"), 2); }
+    }
+    if let Some(warc_writer) = warc_writer {
+        let stats = warc_writer.finish();
+        disp(format!("Archived {} exchanges across {} WARC files ({} failed)", stats.exchanges, stats.files, stats.failed.len()), 1);
+        for (url, e) in &stats.failed { disp(format!("WARC ERROR, couldn't archive {url}: {e}"), 2); }
+    }
+}
+
+///Parses the pages archived in a WARC file, storing them if there's somewhere to store them and listing them if not
+fn replay_warc(warc_path: &std::path::Path, parse_opts: &ParseOptions, store: Option<Box<dyn CrawlStore>>, obey_directives: bool, batch_size: usize, disp: impl Fn(String, u8)) {
+    let pages = match replay(warc_path, parse_opts) {
+        Ok(pages) => pages,
+        Err(e) => fail(format!("WARC ERROR: {e}\nTLDR; Couldn't open the WARC file"))
+    };
+    let writer = store.map(|store| DbWriter::spawn(store, batch_size, Duration::from_secs(5)));
+    let sender = writer.as_ref().map(|writer| writer.sender());
+    let (mut replayed, mut skipped) = (0, 0);
+    for page in pages {
+        let (url, mut parsed) = match page {
+            Ok(page) => page,
+            Err(e) => { disp(format!("WARC ERROR: {e} - stopping there"), 0); break }
+        };
+        if obey_directives && parsed.directives.noindex { skipped += 1; continue }
+        let key = parsed.canonical.take().unwrap_or(url);
+        disp(format!("REPLAYED: {key} ({})", parsed.data.title.as_deref().unwrap_or("untitled")), if sender.is_some() { 3 } else { 1 });
+        if let Some(sender) = &sender { let _ = sender.send(DbMsg::Page(key, Box::new(parsed.data), parsed.links)); }
+        replayed += 1;
+    }
+    disp(format!("Replayed {replayed} pages ({skipped} skipped as noindex)"), 1);
+    if let Some(writer) = writer {
+        let stats = writer.finish();
+        disp(format!("Saved {} sites to the database ({} failed)", stats.written, stats.failed.len()), 1);
+        for (url, e) in &stats.failed { disp(format!("DATABASE ERROR, couldn't save {url}: {e}"), 2); }
     }
 }
 
 //fn parse_args(args: Vec<String>) -> Vec<String> {}
 
-fn get_crawlin(worker_id: u8, site_map: Arc<SiteMap>, pub_links: Arc<LinkList>, options: DispOptions) -> WorkerData {
+///Resolves on SIGINT, or SIGTERM where there is such a thing
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    { let _ = tokio::signal::ctrl_c().await; }
+}
+
+///Everything the crawly bois share - cheap to clone, since it's all Arcs (reqwest Clients share a pool internally)
+#[derive(Clone)]
+struct CrawlShared {
+    client: Client,
+    site_map: Arc<SiteMap>,
+    pub_links: Arc<LinkList>,
+    robots: Option<Arc<RobotsCache>>,
+    scheduler: Arc<HostScheduler>,
+    fetch_opts: Arc<FetchOptions>,
+    parse_opts: Arc<ParseOptions>,
+    obey_directives: bool, //noindex/nofollow
+    max_retries: u32,
+    warc: Option<Sender<WarcMsg>>,
+    options: DispOptions
+}
+
+async fn get_crawlin(worker_id: u16, shared: CrawlShared) -> WorkerData {
     //println!("Initiated!");
     let start = Instant::now();
+    let CrawlShared { client, site_map, pub_links, robots, scheduler, fetch_opts, parse_opts, obey_directives, max_retries, warc, options } = shared;
+    let _shift = pub_links.clock_in(); //Counts us out of the crawl when we're done, even if we panic
     let disp = make_disp(options);
     let mut our_links = LocalUrls::new();
-    let client = blocking::Client::builder().timeout(Duration::from_secs(3)).build().unwrap();
     let mut request_time = Duration::new(0, 0);
+    let (mut nofollow_pages, mut nofollow_links, mut retries) = (0, 0, 0);
     //Debug Timers
     //let mut parsing = Duration::new(0,0);
     //let mut grabbing = Duration::new(0,0);
@@ -117,53 +418,237 @@ fn get_crawlin(worker_id: u8, site_map: Arc<SiteMap>, pub_links: Arc<LinkList>,
     let mut url_checking = Duration::new(0,0);
     //let mut crawling = Duration::new(0,0);
     loop {
+        if pub_links.is_finished() { break }
         //Grab next URL, from local list if possible and public link list if not
         let url_fetch: Instant = Instant::now();
-        let next_url = match our_links.next() {
-            Some(clean_url) => clean_url,
-            None => pub_links.next()
+        let mut next_link = match our_links.next() {
+            Some(link) => link,
+            None => match our_links.next_ready_at() {
+                //Only deferred links left locally, so take a public one if one shows up before they're ready
+                Some(ready_at) => match pub_links.try_next() {
+                    Some(link) => link,
+                    None => {
+                        tokio::select! {
+                            _ = pub_links.wait_for_links() => {},
+                            _ = tokio::time::sleep_until(ready_at.into()) => {}
+                        }
+                        url_fetching += url_fetch.elapsed();
+                        continue;
+                    }
+                },
+                //Nothing left locally at all - wait on the public list, which tells us when the whole crawl is out of links
+                None => match pub_links.next().await {
+                    Some(link) => link,
+                    None => break
+                }
+            }
         };
+        let next_url = &next_link.url;
         url_fetching += url_fetch.elapsed();
         //Check if URL has already been crawled and skip if so
         let url_check = Instant::now();
-        let already_crawled = site_map.contains_key(&next_url);
+        let already_crawled = site_map.contains_key(next_url);
         url_checking += url_check.elapsed();
         if already_crawled { continue }
-        //Crawl page and update relevant objects
+        //Crawl page (if robots.txt lets us) and update relevant objects
         let crawl_time = Instant::now();
-        let crawl_results = crawl(&client, &next_url);
+        if let Some(robots) = &robots {
+            let rules = robots.rules_for(next_url).await;
+            if !rules.is_allowed(next_url.path()) {
+                request_time += crawl_time.elapsed();
+                disp(format!("ROBOTS DISALLOWED: {next_url}"), 3);
+                site_map.insert_bad(next_link.url, FailReason::RobotsDisallowed);
+                continue;
+            }
+            if let Some(delay) = rules.crawl_delay { scheduler.set_delay(next_url.host(), delay); }
+        }
+        //Wait our turn for the host - if it isn't ready, set the url aside and go do something else
+        let permit = match scheduler.try_acquire(next_url.host()) {
+            Ok(permit) => permit,
+            Err(ready_at) => {
+                request_time += crawl_time.elapsed();
+                our_links.defer(next_link, ready_at);
+                continue;
+            }
+        };
+        let bouncer = Bouncer { robots: robots.as_deref(), scheduler: &scheduler };
+        let crawl_results = crawl(&client, &next_link, permit, &bouncer, &fetch_opts, &parse_opts, warc.as_ref()).await;
         request_time += crawl_time.elapsed();
         match crawl_results {
             Ok(mut parsed) => {
-                disp(format!("INSERTING: {}", next_url), 3);
-                if site_map.insert(next_url, parsed.data) { break; };
-                if !parsed.links.is_empty() {
-                    let num_pub_add = cmp::min(pub_links.should_add(), (parsed.links.len() - 1)as u8);
-                    if num_pub_add > 0 { pub_links.add(parsed.links.drain(..num_pub_add as usize).collect())}
+                let mut found = Vec::new();
+                if obey_directives && parsed.directives.nofollow { nofollow_pages += 1; }
+                else {
+                    for link in &parsed.links {
+                        if obey_directives && link.nofollow { nofollow_links += 1; }
+                        else { found.push(next_link.child(link.url.clone())); }
+                    }
+                }
+                //The page is kept under where it says it lives, and everywhere that led to it counts as seen
+                let key = parsed.canonical.take().unwrap_or_else(|| next_link.url.clone());
+                let final_url = CanonicalUrl::from_stored(&parsed.data.final_url);
+                site_map.insert_aliases(parsed.data.redirect_chain.iter().cloned().chain(final_url).chain([next_link.url.clone()]).filter(|url| *url != key));
+                if obey_directives && parsed.directives.noindex {
+                    disp(format!("NOINDEX: {}", next_url), 3);
+                    site_map.insert_bad(key, FailReason::NoIndex);
+                } else {
+                    disp(format!("INSERTING: {key}"), 3);
+                    if site_map.insert(key, parsed.data, parsed.links) {
+                        our_links.extend(found); //Hang onto these so they make it into the saved frontier
+                        pub_links.finish();
+                        break;
+                    };
+                }
+                if !found.is_empty() {
+                    let num_pub_add = cmp::min(pub_links.should_add(), found.len() - 1);
+                    if num_pub_add > 0 { pub_links.add(found.drain(..num_pub_add).collect())}
                 }
                 //Add links we found to local links to crawl
-                our_links.extend(parsed.links);
+                our_links.extend(found);
             },
             Err(e) => {
-                disp(format!("CRAWL ERROR: {e}"), 3);
-                site_map.insert_bad(next_url);
+                //A redirect we couldn't follow counts the same as if the worker had turned the url away itself
+                match e.downcast_ref::<Bounced>() {
+                    Some(Bounced::RobotsDisallowed(hop)) => {
+                        disp(format!("ROBOTS DISALLOWED: {next_url} (redirects to {hop})"), 3);
+                        site_map.insert_bad(next_link.url, FailReason::RobotsDisallowed);
+                        continue;
+                    },
+                    Some(&Bounced::HeldOff(_, ready_at)) => {
+                        our_links.defer(next_link, ready_at);
+                        continue;
+                    },
+                    None => {}
+                }
+                let kind = ErrorKind::classify(&e);
+                let retry_after = e.downcast_ref::<FetchError>().and_then(|fetch_error| fetch_error.retry_after);
+                if let Some(wait) = retry_after { scheduler.hold_off(next_url.host(), Instant::now() + wait); }
+                if kind.is_transient() && next_link.attempts < max_retries && retry_after.is_none_or(|wait| wait <= MAX_RETRY_WAIT) {
+                    let wait = retry_delay(next_link.attempts, retry_after);
+                    disp(format!("RETRYING IN {wait:?} ({e}): {next_url}"), 3);
+                    retries += 1;
+                    next_link.attempts += 1;
+                    our_links.defer(next_link, Instant::now() + wait);
+                } else {
+                    disp(format!("CRAWL ERROR: {e}"), 3);
+                    site_map.insert_failed(next_link.url, kind, next_link.attempts + 1);
+                }
             }
         };
     }
     disp(format!("---CRAB {worker_id}---"), 2);
     disp(format!("Crawling: {:?}\nUrl fetching: {:?}\nUrl checking: {:?}", request_time, url_fetching, url_checking), 2);
-    WorkerData::new(our_links.len(), request_time, start.elapsed())
+    WorkerData { nofollow_pages, nofollow_links, retries, ..WorkerData::new(our_links.into_entries(), request_time, start.elapsed()) }
 }
 
-fn crawl(client: &blocking::Client, cleansed_url: &String) -> Result<ParsedPage> {
-    let url = dirty_url(&cleansed_url);
-    let response = client.get(&url).send()?;
+///Fetches and parses a page. `permit` is our slot on the url's host, which has already been checked against robots.txt.
+async fn crawl(client: &Client, link: &FrontierEntry, permit: HostPermit, bouncer: &Bouncer<'_>, fetch_opts: &FetchOptions, parse_opts: &ParseOptions, warc: Option<&Sender<WarcMsg>>) -> Result<ParsedPage> {
+    let fetched_at = unix_now();
+    let timer = Instant::now();
+    let (mut response, redirect_chain, _permit) = follow_redirects(client, &link.url, permit, bouncer, fetch_opts, &parse_opts.filter, warc).await?;
     match response.status() {
         StatusCode::OK => {
-            let page = response.text()?;
-            let parsed = parse_page(page);
+            let status = response.status().as_u16();
+            let final_url = CanonicalUrl::parse(response.url().as_str(), &parse_opts.filter).unwrap_or_else(|| link.url.clone());
+            let content_type = response.headers().get(CONTENT_TYPE).and_then(|val| val.to_str().ok()).map(String::from);
+            let header_length = response.content_length();
+            let robots_headers: Vec<String> = response.headers().get_all("x-robots-tag").iter().filter_map(|val| val.to_str().ok()).map(String::from).collect();
+            //Pages we won't parse (wrong type, or too big) are still recorded, just without the body
+            let wanted = fetch_opts.accepts(content_type.as_deref()) && header_length.is_none_or(|len| len <= fetch_opts.max_body);
+            let (body, complete) = if wanted { read_capped(&mut response, fetch_opts.max_body).await? } else { (Vec::new(), false) };
+            let latency = timer.elapsed();
+            archive(warc, &response, &body, complete);
+            let content_length = header_length.unwrap_or(body.len() as u64);
+            let (mut parsed, content_hash) = if complete {
+                let page = decode_page(&body, content_type.as_deref(), &final_url);
+                (parse_page(page, &final_url, parse_opts), Some(content_hash(&body)))
+            } else { (ParsedPage::default(), None) };
+            for value in &robots_headers { parsed.directives.add_header(value, ROBOTS_TOKEN); }
+            if parsed.canonical.is_none() && final_url != link.url { parsed.canonical = Some(final_url.clone()); }
+            parsed.data = PageData { status, final_url: final_url.to_string(), redirect_chain, content_type, content_length, fetched_at, latency,
+                depth: link.depth, referrer: link.referrer.clone(), content_hash, raw_body: (fetch_opts.keep_raw && complete).then_some(body), ..parsed.data };
             Ok(parsed)
         },
-        code => Err(Error::msg(format!("Bad Status Code: {code:?}")))
+        code => {
+            if let Some(warc) = warc {
+                let (body, complete) = read_capped(&mut response, fetch_opts.max_body).await.unwrap_or_default();
+                archive(Some(warc), &response, &body, complete);
+            }
+            let retry_after = match code {
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => response.headers().get(RETRY_AFTER).and_then(|val| val.to_str().ok()).and_then(parse_retry_after),
+                _ => None
+            };
+            Err(FetchError { kind: ErrorKind::Status(code.as_u16()), retry_after, msg: format!("Bad Status Code: {code:?}") }.into())
+        }
+    }
+}
+
+///Fetches a url, following any redirects by hand so we know where we've been. Returns the final response
+///along with the urls we were redirected from (empty if there weren't any redirects), and the permit for the host it came from.
+///Redirects are archived here if need be, since the caller only ever sees the last response.
+async fn follow_redirects(client: &Client, url: &CanonicalUrl, mut permit: HostPermit, bouncer: &Bouncer<'_>, fetch_opts: &FetchOptions, filter: &QueryFilter, warc: Option<&Sender<WarcMsg>>)
+    -> Result<(Response, Vec<CanonicalUrl>, HostPermit)> {
+    let mut chain = Vec::new();
+    let mut url = url.clone();
+    loop {
+        let mut response = client.get(url.as_str()).send().await?;
+        if !response.status().is_redirection() { return Ok((response, chain, permit)) }
+        if let Some(warc) = warc {
+            let (body, complete) = read_capped(&mut response, fetch_opts.max_body).await.unwrap_or_default();
+            archive(Some(warc), &response, &body, complete);
+        }
+        let location = response.headers().get(LOCATION).and_then(|val| val.to_str().ok()).and_then(|loc| response.url().join(loc).ok());
+        //A redirect without anywhere (sensible) to go is just a bad status code
+        let Some(next) = location.and_then(|loc| CanonicalUrl::parse(loc.as_str(), filter)) else { return Ok((response, chain, permit)) };
+        chain.push(url);
+        let redirect_error = |msg: String| Err(FetchError { kind: ErrorKind::Redirect, retry_after: None, msg }.into());
+        if chain.contains(&next) { return redirect_error(format!("Redirect loop at {next}")) }
+        if chain.len() > MAX_REDIRECTS { return redirect_error(format!("Over {MAX_REDIRECTS} redirects")) }
+        //Every hop is a request like any other, so it has to get past robots.txt and wait its turn for the host
+        drop(permit); //Let go first - the next hop is often on the same host
+        permit = bouncer.admit(&next).await?;
+        url = next;
+    }
+}
+
+///Checks redirect hops the same way the workers check the urls they pick up
+struct Bouncer<'a> {
+    robots: Option<&'a RobotsCache>,
+    scheduler: &'a Arc<HostScheduler>
+}
+impl Bouncer<'_> {
+    ///Waits for a slot on the url's host, if robots.txt lets us have it at all
+    async fn admit(&self, url: &CanonicalUrl) -> Result<HostPermit> {
+        if let Some(robots) = self.robots {
+            let rules = robots.rules_for(url).await;
+            if !rules.is_allowed(url.path()) {
+                return Err(Bounced::RobotsDisallowed(url.clone()).into())
+            }
+            if let Some(delay) = rules.crawl_delay { self.scheduler.set_delay(url.host(), delay); }
+        }
+        loop {
+            match self.scheduler.try_acquire(url.host()) {
+                Ok(permit) => return Ok(permit),
+                Err(ready_at) if ready_at <= Instant::now() + MAX_RETRY_WAIT => tokio::time::sleep_until(ready_at.into()).await,
+                //The host told us to stay away for a good while - try the whole thing again once it's ready
+                Err(ready_at) => return Err(Bounced::HeldOff(url.clone(), ready_at).into())
+            }
+        }
+    }
+}
+
+///Sends a response off to be written to the WARC files, if we're keeping them
+fn archive(warc: Option<&Sender<WarcMsg>>, response: &Response, body: &[u8], complete: bool) {
+    if let Some(warc) = warc { let _ = warc.send(WarcMsg::Exchange(Box::new(Exchange::new(response, USER_AGENT, body.to_vec(), complete)))); }
+}
+
+///Reads a response body a chunk at a time, stopping once it's read more than `max_bytes`.
+///Returns what it read, and whether that's the whole body.
+async fn read_capped(response: &mut Response, max_bytes: u64) -> Result<(Vec<u8>, bool)> {
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() as u64 > max_bytes { return Ok((body, false)) }
     }
+    Ok((body, true))
 }
\ No newline at end of file
@@ -0,0 +1,107 @@
+use crate::canonical_url::CanonicalUrl;
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8, UTF_16BE, UTF_16LE, WINDOWS_1252};
+use regex::bytes::Regex;
+use std::sync::LazyLock;
+
+///How far into a page we look for a <meta charset>, same as browsers
+const META_PRESCAN_BYTES: usize = 1024;
+
+///Pulls the charset label out of a <meta> tag, built once since every page gets checked
+static META_CHARSET: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?i)<meta\s[^>]*?charset\s*=\s*["']?\s*([a-z0-9_:.\-]+)"#).unwrap());
+
+///Where we got a page's encoding from, strongest first
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EncodingSource {
+    Bom,
+    Header,
+    Meta,
+    Guessed
+}
+
+///Works out which encoding a page is in, going (roughly) the way the HTML spec does it: byte order mark,
+///then the Content-Type header's charset, then a <meta> charset near the top, then a statistical guess
+pub fn sniff_encoding(body: &[u8], content_type: Option<&str>, url: &CanonicalUrl) -> (&'static Encoding, EncodingSource) {
+    if let Some((encoding, _)) = Encoding::for_bom(body) { return (encoding, EncodingSource::Bom) }
+    if let Some(encoding) = content_type.and_then(header_charset) { return (encoding, EncodingSource::Header) }
+    if let Some(encoding) = meta_charset(&body[..body.len().min(META_PRESCAN_BYTES)]) { return (encoding, EncodingSource::Meta) }
+    let mut detector = EncodingDetector::new();
+    detector.feed(body, true);
+    (detector.guess(tld(url), true), EncodingSource::Guessed)
+}
+
+///Decodes a page into UTF-8, whatever it came in as. Bytes that aren't valid in the encoding become U+FFFD.
+pub fn decode_page(body: &[u8], content_type: Option<&str>, url: &CanonicalUrl) -> String {
+    let (encoding, _) = sniff_encoding(body, content_type, url);
+    let (text, _) = encoding.decode_with_bom_removal(body);
+    text.into_owned()
+}
+
+///Pulls the charset parameter out of a Content-Type header, i.e. `text/html; charset="Shift_JIS"`
+fn header_charset(content_type: &str) -> Option<&'static Encoding> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, val) = param.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case("charset") { return None }
+        Encoding::for_label(val.trim().trim_matches(|ch| ch == '"' || ch == '\'').as_bytes())
+    })
+}
+
+///Looks for `<meta charset="...">` or `<meta http-equiv="Content-Type" content="...; charset=...">`
+fn meta_charset(head: &[u8]) -> Option<&'static Encoding> {
+    let label = META_CHARSET.captures(head)?.get(1)?.as_bytes();
+    //A page can't really be UTF-16 if we just read its <meta> as ASCII, so browsers take it to mean UTF-8
+    match Encoding::for_label(label)? {
+        enc if enc == UTF_16BE || enc == UTF_16LE => Some(UTF_8),
+        enc if enc.name() == "x-user-defined" => Some(WINDOWS_1252),
+        enc => Some(enc)
+    }
+}
+
+///The top-level domain of a url's host, which helps the statistical guess (.jp pages are likely Shift_JIS and so on)
+fn tld(url: &CanonicalUrl) -> Option<&[u8]> {
+    let host = url.host().rsplit_once(':').map_or(url.host(), |(host, _)| host);
+    let tld = host.rsplit('.').next()?;
+    //The detector panics on anything that isn't a lowercase label
+    if !tld.is_empty() && tld.bytes().all(|byte| byte.is_ascii_lowercase()) { Some(tld.as_bytes()) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{SHIFT_JIS, EUC_KR};
+    use crate::canonical_url::url;
+    //sniff_encoding Tests
+    #[test]
+    fn bom_beats_everything() {
+        let body = b"\xEF\xBB\xBF<meta charset=\"shift_jis\">hi";
+        assert_eq!(sniff_encoding(body, Some("text/html; charset=windows-1252"), &url("https://gwango.lol/")), (UTF_8, EncodingSource::Bom));
+        assert_eq!(decode_page(body, None, &url("https://gwango.lol/")), "<meta charset=\"shift_jis\">hi");
+    }
+    #[test]
+    fn header_beats_meta() {
+        let body = b"<meta charset=\"shift_jis\">caf\xE9";
+        assert_eq!(sniff_encoding(body, Some("text/html; Charset=\"ISO-8859-1\""), &url("https://gwango.lol/")), (WINDOWS_1252, EncodingSource::Header));
+        assert_eq!(decode_page(body, Some("text/html;charset=latin1"), &url("https://gwango.lol/")), "<meta charset=\"shift_jis\">caf\u{e9}");
+    }
+    #[test]
+    fn meta_charset_forms() {
+        let body = b"<html><head><META http-equiv=\"Content-Type\" content=\"text/html; charset=Shift_JIS\"><title>\x93\xfa\x96\x7b</title>";
+        assert_eq!(sniff_encoding(body, Some("text/html"), &url("https://gwango.lol/")), (SHIFT_JIS, EncodingSource::Meta));
+        assert!(decode_page(body, Some("text/html"), &url("https://gwango.lol/")).contains("<title>\u{65e5}\u{672c}</title>"));
+        assert_eq!(sniff_encoding(b"<meta charset='utf-16'>", None, &url("https://gwango.lol/")), (UTF_8, EncodingSource::Meta));
+    }
+    #[test]
+    fn guesses_without_labels() {
+        let body = "<title>Caf\u{e9} cr\u{e8}me br\u{fb}l\u{e9}e \u{e0} la fran\u{e7}aise</title>".as_bytes();
+        assert_eq!(sniff_encoding(body, None, &url("https://gwango.lol/")), (UTF_8, EncodingSource::Guessed));
+        let (korean, _, _) = EUC_KR.encode("<p>\u{d55c}\u{ad6d}\u{c5b4} \u{d14d}\u{c2a4}\u{d2b8}\u{b294} \u{c5ec}\u{ae30}\u{c5d0} \u{c788}\u{c2b5}\u{b2c8}\u{b2e4}</p>");
+        assert_eq!(sniff_encoding(&korean, None, &url("http://127.0.0.1:8080/")).0, EUC_KR);
+        assert_eq!(decode_page(&korean, None, &url("https://gwango.kr/")), "<p>\u{d55c}\u{ad6d}\u{c5b4} \u{d14d}\u{c2a4}\u{d2b8}\u{b294} \u{c5ec}\u{ae30}\u{c5d0} \u{c788}\u{c2b5}\u{b2c8}\u{b2e4}</p>");
+    }
+    #[test]
+    fn tld_only_when_safe() {
+        assert_eq!(tld(&url("https://gwango.co.jp:8443/")), Some(&b"jp"[..]));
+        assert_eq!(tld(&url("http://127.0.0.1/")), None);
+        assert_eq!(tld(&url("http://localhost/")), Some(&b"localhost"[..]));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+///How long to wait before rechecking a host that's at its connection limit
+const BUSY_RECHECK: Duration = Duration::from_millis(100);
+///Longest Crawl-delay we'll honour - anything longer would just park a host's urls for the rest of the run
+pub const MAX_CRAWL_DELAY: Duration = Duration::from_secs(60);
+///Hosts we'll keep track of before clearing out the ones we're done waiting on
+const MIN_SWEEP: usize = 1024;
+
+struct HostState {
+    next_allowed: Instant,
+    active: usize,
+    delay: Option<Duration> //Set from robots.txt Crawl-delay, overrides the default
+}
+
+///Keeps track of when each host may next be hit and how many requests it currently has in flight
+pub struct HostScheduler {
+    hosts: Mutex<HashMap<String, HostState>>,
+    sweep_at: AtomicUsize, //Number of hosts at which to next clear out idle ones
+    min_delay: Duration,
+    max_conns: usize
+}
+impl HostScheduler {
+    pub fn new(min_delay: Duration, max_conns: usize) -> HostScheduler {
+        HostScheduler { hosts: Mutex::new(HashMap::new()), sweep_at: AtomicUsize::new(MIN_SWEEP), min_delay, max_conns: cmp::max(max_conns, 1) }
+    }
+    ///Overrides the delay for a host (i.e. with its robots.txt Crawl-delay), up to MAX_CRAWL_DELAY
+    pub fn set_delay(&self, host: &str, delay: Duration) {
+        let mut hosts = self.hosts.lock();
+        let state = hosts.entry(host.to_string()).or_insert_with(|| HostState { next_allowed: Instant::now(), active: 0, delay: None });
+        state.delay = Some(delay.min(MAX_CRAWL_DELAY));
+    }
+    ///Claims a connection slot for a host, or returns when it's next worth trying again
+    pub fn try_acquire(self: &Arc<Self>, host: &str) -> Result<HostPermit, Instant> {
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock();
+        if hosts.len() >= self.sweep_at.load(AtomicOrdering::Relaxed) {
+            //Hosts with nothing in flight and no wait left have nothing worth remembering (a Crawl-delay is set again before each request)
+            hosts.retain(|_, state| state.active > 0 || state.next_allowed > now);
+            self.sweep_at.store(cmp::max(hosts.len() * 2, MIN_SWEEP), AtomicOrdering::Relaxed);
+        }
+        let state = hosts.entry(host.to_string()).or_insert_with(|| HostState { next_allowed: now, active: 0, delay: None });
+        if state.next_allowed > now { return Err(state.next_allowed) }
+        if state.active >= self.max_conns { return Err(now + BUSY_RECHECK) }
+        state.active += 1;
+        state.next_allowed = now + state.delay.unwrap_or(self.min_delay);
+        Ok(HostPermit { scheduler: self.clone(), host: host.to_string() })
+    }
+    ///Keeps everyone off a host until `until` (i.e. when it's told us to come back later with Retry-After)
+    pub fn hold_off(&self, host: &str, until: Instant) {
+        let mut hosts = self.hosts.lock();
+        let state = hosts.entry(host.to_string()).or_insert_with(|| HostState { next_allowed: until, active: 0, delay: None });
+        state.next_allowed = cmp::max(state.next_allowed, until);
+    }
+    fn release(&self, host: &str) {
+        let mut hosts = self.hosts.lock();
+        if let Some(state) = hosts.get_mut(host) { state.active -= 1; }
+    }
+}
+
+///A claimed connection slot for a host, given back when dropped
+pub struct HostPermit {
+    scheduler: Arc<HostScheduler>,
+    host: String
+}
+impl Drop for HostPermit {
+    fn drop(&mut self) { self.scheduler.release(&self.host) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    //HostScheduler Tests
+    #[test]
+    fn delay_blocks_host() {
+        let sched = Arc::new(HostScheduler::new(Duration::from_secs(3600), 5));
+        let permit = sched.try_acquire("gwango.lol");
+        assert!(permit.is_ok());
+        drop(permit);
+        match sched.try_acquire("gwango.lol") {
+            Err(ready_at) => assert!(ready_at > Instant::now() + Duration::from_secs(3000)),
+            Ok(_) => panic!("Host should still be cooling down")
+        }
+        assert!(sched.try_acquire("google.com").is_ok()); //Other hosts are unaffected
+    }
+    #[test]
+    fn max_conns_per_host() {
+        let sched = Arc::new(HostScheduler::new(Duration::ZERO, 2));
+        let first = sched.try_acquire("gwango.lol").ok();
+        let second = sched.try_acquire("gwango.lol").ok();
+        assert!(first.is_some() && second.is_some());
+        assert!(sched.try_acquire("gwango.lol").is_err());
+        drop(first);
+        assert!(sched.try_acquire("gwango.lol").is_ok());
+    }
+    #[test]
+    fn hold_off_host() {
+        let sched = Arc::new(HostScheduler::new(Duration::ZERO, 5));
+        sched.hold_off("gwango.lol", Instant::now() + Duration::from_secs(3600));
+        assert!(sched.try_acquire("gwango.lol").is_err());
+        sched.hold_off("google.com", Instant::now() - Duration::from_secs(1));
+        assert!(sched.try_acquire("google.com").is_ok());
+    }
+    #[test]
+    fn crawl_delay_overrides() {
+        let sched = Arc::new(HostScheduler::new(Duration::ZERO, 5));
+        sched.set_delay("gwango.lol", Duration::from_secs(3600));
+        assert!(sched.try_acquire("gwango.lol").is_ok());
+        assert!(sched.try_acquire("gwango.lol").is_err());
+        assert!(sched.try_acquire("google.com").is_ok());
+        assert!(sched.try_acquire("google.com").is_ok());
+    }
+    #[test]
+    fn crawl_delay_capped() {
+        let sched = Arc::new(HostScheduler::new(Duration::ZERO, 5));
+        sched.set_delay("gwango.lol", Duration::from_secs(86400));
+        assert!(sched.try_acquire("gwango.lol").is_ok());
+        match sched.try_acquire("gwango.lol") {
+            Err(ready_at) => assert!(ready_at <= Instant::now() + MAX_CRAWL_DELAY),
+            Ok(_) => panic!("Host should still be cooling down")
+        }
+    }
+    #[test]
+    fn forgets_idle_hosts() {
+        let sched = Arc::new(HostScheduler::new(Duration::ZERO, 5));
+        let busy = sched.try_acquire("busy.lol").unwrap();
+        for host in 0..MIN_SWEEP * 3 { drop(sched.try_acquire(&format!("host{host}.lol"))); }
+        let hosts = sched.hosts.lock();
+        assert!(hosts.len() < MIN_SWEEP * 2);
+        assert!(hosts.contains_key("busy.lol")); //Still has a request in flight
+        drop(hosts);
+        drop(busy);
+    }
+}
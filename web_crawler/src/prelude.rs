@@ -1,6 +1,6 @@
 pub use anyhow::{Result, Error};
 pub use std::collections::{VecDeque, HashMap, HashSet};
-pub use parking_lot::{Mutex, RwLock, Condvar, RwLockReadGuard};
+pub use parking_lot::{Mutex, RwLock};
 pub use std::sync::Arc;
 pub use std::thread;
 pub use std::time::{Duration, Instant};
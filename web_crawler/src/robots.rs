@@ -0,0 +1,321 @@
+use crate::prelude::*;
+use crate::canonical_url::CanonicalUrl;
+use crate::crawler_datatypes::ErrorKind;
+use crate::crawler_utilities::{retry_delay, MAX_RETRY_WAIT};
+use reqwest::Client;
+use tokio::sync::OnceCell;
+
+///Product token we look for in User-agent lines
+pub const ROBOTS_TOKEN: &str = env!("CARGO_PKG_NAME");
+///Most of a robots.txt we'll read - RFC 9309 asks for at least 500 KiB to be parsed
+const MAX_ROBOTS_BYTES: usize = 500 * 1024;
+
+struct Rule {
+    allow: bool,
+    pattern: String
+}
+
+///The rules from a robots.txt that apply to us
+pub struct RobotRules {
+    rules: Vec<Rule>,
+    pub crawl_delay: Option<Duration>
+}
+impl RobotRules {
+    pub fn allow_all() -> RobotRules { RobotRules { rules: Vec::new(), crawl_delay: None } }
+    pub fn disallow_all() -> RobotRules {
+        RobotRules { rules: vec![Rule { allow: false, pattern: "/".to_string() }], crawl_delay: None }
+    }
+    ///Parses a robots.txt, keeping only the groups addressed to `token` (or to `*` if no group names us)
+    pub fn parse(robots_txt: &str, token: &str) -> RobotRules {
+        let token = token.to_lowercase();
+        let mut ours = RobotRules::allow_all();
+        let mut star = RobotRules::allow_all();
+        let mut found_ours = false;
+        //State of the group currently being read
+        let (mut for_us, mut for_star, mut in_agents) = (false, false, false);
+        for line in robots_txt.lines() {
+            let line = match line.find('#') { Some(idx) => &line[..idx], None => line };
+            let Some((key, val)) = line.split_once(':') else { continue };
+            let (key, val) = (key.trim().to_lowercase(), val.trim());
+            match key.as_str() {
+                "user-agent" => {
+                    //A User-agent line after rules starts a new group
+                    if !in_agents { (for_us, for_star) = (false, false); }
+                    in_agents = true;
+                    let agent = val.to_lowercase();
+                    if agent == "*" { for_star = true; }
+                    else if agent == token { for_us = true; found_ours = true; }
+                }
+                "allow" | "disallow" => {
+                    in_agents = false;
+                    if val.is_empty() { continue } //An empty Disallow means allow everything
+                    let rule = || Rule { allow: key == "allow", pattern: val.to_string() };
+                    if for_us { ours.rules.push(rule()); }
+                    if for_star { star.rules.push(rule()); }
+                }
+                "crawl-delay" => {
+                    in_agents = false;
+                    let delay = val.parse::<f64>().ok().filter(|secs| secs.is_finite() && *secs >= 0.0).map(Duration::from_secs_f64);
+                    if for_us { ours.crawl_delay = delay; }
+                    if for_star { star.crawl_delay = delay; }
+                }
+                _ => {} //Sitemap and friends aren't group members
+            }
+        }
+        if found_ours { ours } else { star }
+    }
+    ///Checks a path (with its query, if any) against the rules - longest match wins, Allow wins ties
+    pub fn is_allowed(&self, path: &str) -> bool {
+        if path == "/robots.txt" { return true }
+        let mut best: Option<&Rule> = None;
+        for rule in &self.rules {
+            if !pattern_matches(&rule.pattern, path) { continue }
+            best = match best {
+                Some(curr) if curr.pattern.len() > rule.pattern.len() => Some(curr),
+                Some(curr) if curr.pattern.len() == rule.pattern.len() && curr.allow => Some(curr),
+                _ => Some(rule)
+            };
+        }
+        best.is_none_or(|rule| rule.allow)
+    }
+}
+
+///Matches a robots.txt path pattern, where `*` is any run of characters and a trailing `$` anchors the end
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(stripped) => (stripped, true),
+        None => (pattern, false)
+    };
+    let pattern = pattern.as_bytes();
+    let path = path.as_bytes();
+    //reachable[i] is true if the pattern so far can consume exactly the first i bytes of path
+    let mut reachable = vec![false; path.len() + 1];
+    reachable[0] = true;
+    for &pch in pattern {
+        if pch == b'*' {
+            for idx in 1..reachable.len() { reachable[idx] |= reachable[idx - 1]; }
+        } else {
+            for idx in (1..reachable.len()).rev() { reachable[idx] = reachable[idx - 1] && path[idx - 1] == pch; }
+            reachable[0] = false;
+        }
+    }
+    if anchored { reachable[path.len()] } else { reachable.contains(&true) }
+}
+
+///Page-level directives from <meta name="robots"> tags and X-Robots-Tag headers
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RobotsDirectives {
+    pub noindex: bool, //Don't store the page
+    pub nofollow: bool //Don't follow any of the page's links
+}
+impl RobotsDirectives {
+    ///Adds a comma separated list of directives, like `noindex, nofollow`
+    pub fn add(&mut self, directives: &str) {
+        for directive in directives.split(',').map(|directive| directive.trim().to_lowercase()) {
+            match directive.as_str() {
+                "noindex" => self.noindex = true,
+                "nofollow" => self.nofollow = true,
+                "none" => (self.noindex, self.nofollow) = (true, true),
+                _ => {} //all, noarchive, max-snippet and friends don't matter to us
+            }
+        }
+    }
+    ///Adds an X-Robots-Tag header value, which may be addressed to a particular crawler (`somebot: noindex`)
+    pub fn add_header(&mut self, value: &str, token: &str) {
+        match value.split_once(':') {
+            //unavailable_after is the one directive with a colon in it
+            Some((agent, directives)) if !agent.contains(',') && !agent.trim().eq_ignore_ascii_case("unavailable_after") => {
+                if agent.trim().eq_ignore_ascii_case(token) { self.add(directives) }
+            },
+            _ => self.add(value)
+        }
+    }
+}
+
+///Per-origin cache of robots.txt rules, fetched the first time an origin is seen
+pub struct RobotsCache {
+    hosts: RwLock<HashMap<String, Arc<OnceCell<FetchedRules>>>>,
+    client: Client, //Should follow redirects, unlike the page client
+    max_retries: u32
+}
+///Rules as fetched, and when to fetch them again if they're only standing in for a robots.txt we couldn't get
+struct FetchedRules {
+    rules: Arc<RobotRules>,
+    expires: Option<Instant>
+}
+impl RobotsCache {
+    ///Unreachable robots.txt files are retried up to `max_retries` times, backing off like page fetches do
+    pub fn new(client: Client, max_retries: u32) -> RobotsCache { RobotsCache { hosts: RwLock::new(HashMap::new()), client, max_retries } }
+    ///Fetches (or looks up) the rules for the origin of a url
+    pub async fn rules_for(&self, url: &CanonicalUrl) -> Arc<RobotRules> {
+        let origin = url.origin();
+        let cell = {
+            let mut hosts = self.hosts.write();
+            hosts.entry(origin.to_string()).or_default().clone()
+        }; //Lock must be dropped before awaiting
+        let fetched = cell.get_or_init(|| self.fetch_rules(origin)).await;
+        if fetched.expires.is_none_or(|expires| expires > Instant::now()) { return fetched.rules.clone() }
+        //We couldn't get at it last time and that was a while ago, so give it another go (unless someone else already is)
+        let fresh = {
+            let mut hosts = self.hosts.write();
+            let entry = hosts.entry(origin.to_string()).or_default();
+            if Arc::ptr_eq(entry, &cell) { *entry = Arc::default(); }
+            entry.clone()
+        };
+        fresh.get_or_init(|| self.fetch_rules(origin)).await.rules.clone()
+    }
+    ///Grabs a host's robots.txt. Per RFC 9309, a 4xx means there are no rules, while a 5xx or a robots.txt
+    ///we can't get at all (the host is down, the connection drops...) means stay out entirely - for now.
+    async fn fetch_rules(&self, origin: &str) -> FetchedRules {
+        let url = format!("{origin}/robots.txt");
+        let mut attempt = 0;
+        loop {
+            let kind = match self.client.get(&url).send().await {
+                Ok(response) if response.status().is_success() => match read_robots(response).await {
+                    Ok(body) => return FetchedRules { rules: Arc::new(RobotRules::parse(&body, ROBOTS_TOKEN)), expires: None },
+                    Err(e) => ErrorKind::classify(&e)
+                },
+                //A 3xx that can't be followed (no Location) is unavailable, same as a 4xx
+                Ok(response) if response.status().is_client_error() || response.status().is_redirection() => return FetchedRules { rules: Arc::new(RobotRules::allow_all()), expires: None },
+                Ok(response) => ErrorKind::Status(response.status().as_u16()),
+                //And so is one behind too many redirects, which the client gives up on itself
+                Err(e) if e.is_redirect() => return FetchedRules { rules: Arc::new(RobotRules::allow_all()), expires: None },
+                Err(e) => ErrorKind::classify(&e.into())
+            };
+            if !kind.is_transient() || attempt >= self.max_retries {
+                return FetchedRules { rules: Arc::new(RobotRules::disallow_all()), expires: Some(Instant::now() + MAX_RETRY_WAIT) }
+            }
+            tokio::time::sleep(retry_delay(attempt, None)).await;
+            attempt += 1;
+        }
+    }
+}
+
+///Reads up to MAX_ROBOTS_BYTES of a robots.txt, dropping any line the cap cuts in half
+async fn read_robots(mut response: reqwest::Response) -> Result<String> {
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_ROBOTS_BYTES {
+            body.truncate(MAX_ROBOTS_BYTES);
+            body.truncate(body.iter().rposition(|&byte| byte == b'\n').map_or(0, |idx| idx + 1));
+            break;
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    //pattern_matches Tests
+    #[test]
+    fn pattern_prefix() {
+        assert!(pattern_matches("/private", "/private/stuff.html"));
+        assert!(!pattern_matches("/private", "/public/private"));
+    }
+    #[test]
+    fn pattern_wildcards() {
+        assert!(pattern_matches("/*.php", "/dir/index.php?x=1"));
+        assert!(pattern_matches("/*.php$", "/index.php"));
+        assert!(!pattern_matches("/*.php$", "/index.php?x=1"));
+        assert!(pattern_matches("/a*b*c", "/aXXbYYc/more"));
+        assert!(!pattern_matches("/a*b*c", "/aXXcYYb"));
+    }
+    //RobotRules Tests
+    #[test]
+    fn parse_picks_our_group() {
+        let txt = "User-agent: *\nDisallow: /\n\nUser-agent: web_crawler\nDisallow: /secret\nCrawl-delay: 2.5";
+        let rules = RobotRules::parse(txt, ROBOTS_TOKEN);
+        assert!(rules.is_allowed("/public"));
+        assert!(!rules.is_allowed("/secret/page"));
+        assert_eq!(rules.crawl_delay, Some(Duration::from_millis(2500)));
+    }
+    #[test]
+    fn parse_falls_back_to_star() {
+        let txt = "User-agent: googlebot\nDisallow: /\n\nUser-agent: otherbot\nUser-agent: *\nDisallow: /tmp # comment\nDisallow:";
+        let rules = RobotRules::parse(txt, ROBOTS_TOKEN);
+        assert!(rules.is_allowed("/index.html"));
+        assert!(!rules.is_allowed("/tmp/file"));
+        assert_eq!(rules.crawl_delay, None);
+    }
+    #[test]
+    fn parse_needs_whole_token() {
+        //Neither an empty agent nor a bit of our name counts as addressing us
+        let txt = "User-agent:\nDisallow: /\n\nUser-agent: web\nDisallow: /\n\nUser-agent: *\nDisallow: /tmp";
+        let rules = RobotRules::parse(txt, ROBOTS_TOKEN);
+        assert!(rules.is_allowed("/index.html"));
+        assert!(!rules.is_allowed("/tmp/file"));
+        assert!(!RobotRules::parse("User-agent: WEB_CRAWLER\nDisallow: /", ROBOTS_TOKEN).is_allowed("/index.html"));
+    }
+    #[test]
+    fn longest_match_wins() {
+        let txt = "User-agent: *\nDisallow: /docs\nAllow: /docs/public\nAllow: /page\nDisallow: /page";
+        let rules = RobotRules::parse(txt, ROBOTS_TOKEN);
+        assert!(!rules.is_allowed("/docs/private"));
+        assert!(rules.is_allowed("/docs/public/a.html"));
+        assert!(rules.is_allowed("/page")); //Allow wins ties
+    }
+    //RobotsCache Tests
+    ///Answers each connection with the next canned response (the last one over and over), returning the origin to use
+    fn serve(responses: Vec<&'static str>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (idx, stream) in listener.incoming().enumerate() {
+                let Ok(mut stream) = stream else { return };
+                let mut request = [0; 4096];
+                let _ = std::io::Read::read(&mut stream, &mut request);
+                let _ = std::io::Write::write_all(&mut stream, responses[idx.min(responses.len() - 1)].as_bytes());
+            }
+        });
+        origin
+    }
+    fn robots_url(origin: &str) -> CanonicalUrl { CanonicalUrl::from_stored(&format!("{origin}/page")).unwrap() }
+    #[tokio::test]
+    async fn robots_retries_server_errors() {
+        let origin = serve(vec!["HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 29\r\nConnection: close\r\n\r\nUser-agent: *\nDisallow: /tmp\n"]);
+        let cache = RobotsCache::new(Client::new(), 1);
+        let rules = cache.rules_for(&robots_url(&origin)).await;
+        assert!(rules.is_allowed("/page") && !rules.is_allowed("/tmp/a"));
+    }
+    #[tokio::test]
+    async fn robots_unreachable_expires() {
+        let origin = serve(vec!["HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"]);
+        let cache = RobotsCache::new(Client::new(), 0);
+        assert!(!cache.rules_for(&robots_url(&origin)).await.is_allowed("/page"));
+        let cell = cache.hosts.read().get(&origin).unwrap().clone();
+        assert!(cell.get().unwrap().expires.is_some()); //Tried again once it's been a while
+    }
+    #[tokio::test]
+    async fn robots_redirect_loop_allows() {
+        let origin = serve(vec!["HTTP/1.1 302 Found\r\nLocation: /robots.txt\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"]);
+        let cache = RobotsCache::new(Client::new(), 0);
+        assert!(cache.rules_for(&robots_url(&origin)).await.is_allowed("/page"));
+    }
+    //RobotsDirectives Tests
+    #[test]
+    fn directives_add() {
+        let mut directives = RobotsDirectives::default();
+        directives.add("index, NoFollow, max-snippet:50");
+        assert_eq!(directives, RobotsDirectives { noindex: false, nofollow: true });
+        directives.add("none");
+        assert_eq!(directives, RobotsDirectives { noindex: true, nofollow: true });
+    }
+    #[test]
+    fn directives_header_agents() {
+        let mut directives = RobotsDirectives::default();
+        directives.add_header("googlebot: noindex", ROBOTS_TOKEN);
+        directives.add_header("unavailable_after: 25 Jun 2010 15:00:00 PST", ROBOTS_TOKEN);
+        assert_eq!(directives, RobotsDirectives::default());
+        directives.add_header("Web_Crawler: nofollow", ROBOTS_TOKEN);
+        directives.add_header("noindex, unavailable_after: 25 Jun 2010", ROBOTS_TOKEN);
+        assert_eq!(directives, RobotsDirectives { noindex: true, nofollow: true });
+    }
+    #[test]
+    fn robots_txt_always_allowed() {
+        assert!(RobotRules::disallow_all().is_allowed("/robots.txt"));
+        assert!(!RobotRules::disallow_all().is_allowed("/"));
+    }
+}
@@ -71,12 +71,12 @@ impl UrlTree {
                 let curr_char = url.chars().nth(slf.level as usize).expect("URL shorter than it should be...");
                 match slf.branches.get(&curr_char) {
                     Some(tree) => tree.clone().check_url(url),
-                    None => return false //Branch doesn't exist
+                    None => false //Branch doesn't exist
                 }
             },
             UrlTree::Leaf(bucket) => {
                 let slf = bucket.read();
-                slf.contains(&url)
+                slf.contains(url)
             }
         }
     }
@@ -0,0 +1,406 @@
+use crate::prelude::*;
+use crate::crawler_datatypes::{ParsedPage, ParseOptions, PageData};
+use crate::crawler_utilities::{parse_page, content_hash};
+use crate::canonical_url::CanonicalUrl;
+use crate::robots::ROBOTS_TOKEN;
+use crate::crawl_store::unix_now;
+use crate::page_encoding::decode_page;
+use flate2::{Compression, write::GzEncoder, read::MultiGzDecoder};
+use rand::Rng;
+use reqwest::{Response, header::{HeaderMap, CONTENT_LENGTH, TRANSFER_ENCODING}};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+///Default size a WARC file can grow to before we start the next one
+pub const DEFAULT_WARC_MAX_SIZE: u64 = 1 << 30;
+
+///One request and the response it got, as it came over the wire
+pub struct Exchange {
+    pub url: String,
+    pub ip: Option<IpAddr>,
+    pub fetched_at: i64, //Unix seconds
+    pub request: Vec<u8>, //Request line and headers
+    pub response: Vec<u8>, //Status line and headers
+    pub body: Vec<u8>,
+    pub truncated: bool //Whether we stopped reading (or never started) before the end of the body
+}
+impl Exchange {
+    ///Captures a response and however much of its body we read. reqwest doesn't hand back the request it actually sent,
+    ///so that one is rebuilt from the url and the headers we always send.
+    pub fn new(response: &Response, user_agent: &str, body: Vec<u8>, complete: bool) -> Exchange {
+        let url = response.url();
+        let target = &url[url::Position::BeforePath..url::Position::AfterQuery];
+        let request = format!("GET {target} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {user_agent}\r\nAccept: */*\r\n\r\n", &url[url::Position::BeforeHost..url::Position::AfterPort]);
+        let status_line = format!("{:?} {}", response.version(), response.status());
+        let head = response_head(&status_line, response.headers(), complete.then_some(body.len()));
+        Exchange { url: url.to_string(), ip: response.remote_addr().map(|addr| addr.ip()), fetched_at: unix_now(), request: request.into_bytes(), response: head, body, truncated: !complete }
+    }
+}
+
+///The status line and headers of a response, squared up with the body we actually store. reqwest has already undone any
+///chunking, so Transfer-Encoding goes and Content-Length is set to the body's real length (left alone if we didn't read it all).
+fn response_head(status_line: &str, headers: &HeaderMap, body_len: Option<usize>) -> Vec<u8> {
+    let mut head = format!("{status_line}\r\n").into_bytes();
+    for (name, val) in headers {
+        if *name == TRANSFER_ENCODING || (*name == CONTENT_LENGTH && body_len.is_some()) { continue }
+        head.extend_from_slice(format!("{name}: ").as_bytes());
+        head.extend_from_slice(val.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    if let Some(len) = body_len { head.extend_from_slice(format!("content-length: {len}\r\n").as_bytes()); }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+///Things the crawlers send the WARC writer
+pub enum WarcMsg {
+    Exchange(Box<Exchange>),
+    Stop
+}
+
+///What the WARC writer managed to do
+#[derive(Default, Debug)]
+pub struct WarcStats {
+    pub exchanges: usize,
+    pub files: usize,
+    pub failed: Vec<(String, String)> //Exchanges that couldn't be written, and why
+}
+
+///Handle to the thread that writes raw exchanges out as WARC 1.1, one gzip member per record so the files
+///can be read (or split up) record by record. Once a file passes `max_size`, the next record starts a new one.
+pub struct WarcWriter {
+    sender: Sender<WarcMsg>,
+    handle: thread::JoinHandle<WarcStats>
+}
+impl WarcWriter {
+    ///Opens the first file straight away, so a bad path shows up before the crawl rather than after it
+    pub fn spawn(path: &Path, max_size: u64) -> Result<WarcWriter> {
+        let mut archive = WarcFiles::new(path, max_size);
+        archive.rotate()?;
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || write_loop(archive, receiver));
+        Ok(WarcWriter { sender, handle })
+    }
+    pub fn sender(&self) -> Sender<WarcMsg> { self.sender.clone() }
+    ///Writes whatever is left, stops the writer thread and returns how the writing went overall
+    pub fn finish(self) -> WarcStats {
+        let _ = self.sender.send(WarcMsg::Stop);
+        self.handle.join().expect("WARC writer panicked :(")
+    }
+}
+
+fn write_loop(mut archive: WarcFiles, receiver: Receiver<WarcMsg>) -> WarcStats {
+    let mut stats = WarcStats::default();
+    while let Ok(WarcMsg::Exchange(exchange)) = receiver.recv() {
+        match archive.write_exchange(&exchange) {
+            Ok(()) => stats.exchanges += 1,
+            Err(e) => stats.failed.push((exchange.url, e.to_string()))
+        }
+    }
+    if let Err(e) = archive.flush() { stats.failed.push((archive.current_name(), e.to_string())); }
+    stats.files = archive.index;
+    stats
+}
+
+///The run of numbered files a crawl's WARC output goes into, i.e. crawl-00000.warc.gz, crawl-00001.warc.gz...
+struct WarcFiles {
+    base: String,
+    max_size: u64,
+    index: usize, //Number of files opened so far
+    file: Option<BufWriter<File>>,
+    size: u64,
+    exchanges: usize //In the current file
+}
+impl WarcFiles {
+    fn new(path: &Path, max_size: u64) -> WarcFiles {
+        let path = path.to_string_lossy();
+        let base = path.strip_suffix(".warc.gz").or_else(|| path.strip_suffix(".warc")).unwrap_or(&path).to_string();
+        WarcFiles { base, max_size, index: 0, file: None, size: 0, exchanges: 0 }
+    }
+    fn current_name(&self) -> String { format!("{}-{:05}.warc.gz", self.base, self.index.saturating_sub(1)) }
+    ///Closes off the current file and starts the next, which opens with a warcinfo record saying what made it
+    fn rotate(&mut self) -> Result<()> {
+        self.flush()?;
+        self.index += 1;
+        let name = self.current_name();
+        self.file = Some(BufWriter::new(File::create(&name)?));
+        self.size = 0;
+        self.exchanges = 0;
+        let filename = name.rsplit(['/', '\\']).next().unwrap_or(&name).to_string();
+        let info = format!("software: {}/{}\r\nformat: WARC File Format 1.1\r\nconformsTo: http://iipc.github.io/warc-specifications/specifications/warc-format/warc-1.1/\r\n",
+            env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        self.write_record(&[("WARC-Type", "warcinfo".to_string()), ("WARC-Record-ID", record_id()), ("WARC-Date", warc_date(unix_now())),
+            ("WARC-Filename", filename), ("Content-Type", "application/warc-fields".to_string())], info.as_bytes())
+    }
+    fn write_exchange(&mut self, exchange: &Exchange) -> Result<()> {
+        //Every file gets at least one exchange, however small max_size is
+        if self.size >= self.max_size && self.exchanges > 0 { self.rotate()?; }
+        self.exchanges += 1;
+        let response_id = record_id();
+        let date = warc_date(exchange.fetched_at);
+        let mut headers = vec![("WARC-Type", "response".to_string()), ("WARC-Record-ID", response_id.clone()), ("WARC-Date", date.clone()),
+            ("WARC-Target-URI", exchange.url.clone()), ("Content-Type", "application/http;msgtype=response".to_string())];
+        if let Some(ip) = exchange.ip { headers.push(("WARC-IP-Address", ip.to_string())); }
+        if exchange.truncated { headers.push(("WARC-Truncated", "length".to_string())); }
+        self.write_record(&headers, &[&exchange.response[..], &exchange.body].concat())?;
+        self.write_record(&[("WARC-Type", "request".to_string()), ("WARC-Record-ID", record_id()), ("WARC-Date", date), ("WARC-Target-URI", exchange.url.clone()),
+            ("WARC-Concurrent-To", response_id), ("Content-Type", "application/http;msgtype=request".to_string())], &exchange.request)
+    }
+    fn write_record(&mut self, headers: &[(&str, String)], block: &[u8]) -> Result<()> {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(b"WARC/1.1\r\n")?;
+        for (name, val) in headers { write!(gz, "{name}: {val}\r\n")?; }
+        write!(gz, "Content-Length: {}\r\n\r\n", block.len())?;
+        gz.write_all(block)?;
+        gz.write_all(b"\r\n\r\n")?;
+        let member = gz.finish()?;
+        let Some(file) = &mut self.file else { return Err(anyhow::anyhow!("No WARC file open")) };
+        file.write_all(&member)?;
+        self.size += member.len() as u64;
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<()> {
+        if let Some(file) = &mut self.file { file.flush()?; }
+        Ok(())
+    }
+}
+
+///A random (version 4) UUID, which is what WARC records are usually named by
+fn record_id() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("<urn:uuid:{}-{}-{}-{}-{}>", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+///Unix seconds as the UTC timestamp WARC wants, i.e. 2024-03-01T12:30:00Z
+fn warc_date(unix_secs: i64) -> String {
+    //Days to a civil date, per Howard Hinnant's algorithm
+    let (days, secs) = (unix_secs.div_euclid(86_400), unix_secs.rem_euclid(86_400));
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+///The other way round - `None` if it's not a timestamp of that shape
+fn parse_warc_date(date: &str) -> Option<i64> {
+    let num = |range: std::ops::Range<usize>| date.get(range)?.parse::<i64>().ok();
+    if date.len() < 20 || !date.ends_with('Z') { return None }
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, min, sec) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    //And civil date back to days, again per Hinnant
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Some(days * 86_400 + hour * 3600 + min * 60 + sec)
+}
+
+///A WARC record: its named fields, and the block of content after them
+pub struct WarcRecord {
+    pub headers: Vec<(String, String)>,
+    pub block: Vec<u8>
+}
+impl WarcRecord {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, val)| val.as_str())
+    }
+}
+
+///Reads records back out of a WARC file, gzipped or not. Stops at the first record it can't make sense of.
+pub struct WarcReader {
+    input: Box<dyn BufRead>,
+    broken: bool
+}
+impl WarcReader {
+    pub fn open(path: &Path) -> Result<WarcReader> {
+        let file = File::open(path)?;
+        let gzipped = path.extension().is_some_and(|ext| ext == "gz");
+        Ok(WarcReader::new(if gzipped { Box::new(BufReader::new(MultiGzDecoder::new(file))) } else { Box::new(BufReader::new(file)) }))
+    }
+    pub fn new(input: Box<dyn BufRead>) -> WarcReader { WarcReader { input, broken: false } }
+    fn read_record(&mut self) -> Result<Option<WarcRecord>> {
+        //Skip any blank lines left between records
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.input.read_line(&mut line)? == 0 { return Ok(None) }
+            if !line.trim().is_empty() { break }
+        }
+        if !line.starts_with("WARC/") { return Err(anyhow::anyhow!("Expected a WARC record, found {:?}", line.trim_end())) }
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if self.input.read_line(&mut line)? == 0 { return Err(anyhow::anyhow!("WARC record cut off in its headers")) }
+            let field = line.trim_end();
+            if field.is_empty() { break }
+            let Some((name, val)) = field.split_once(':') else { return Err(anyhow::anyhow!("Bad WARC header {field:?}")) };
+            headers.push((name.trim().to_string(), val.trim().to_string()));
+        }
+        let record = WarcRecord { headers, block: Vec::new() };
+        let length: u64 = record.header("Content-Length").and_then(|len| len.parse().ok()).ok_or_else(|| anyhow::anyhow!("WARC record without a Content-Length"))?;
+        //Read as we go rather than trusting the length up front - a bogus one shouldn't get to allocate gigabytes
+        let mut block = Vec::new();
+        (&mut self.input).take(length).read_to_end(&mut block)?;
+        if (block.len() as u64) < length { return Err(anyhow::anyhow!("WARC record cut off {} bytes into its {length} byte block", block.len())) }
+        Ok(Some(WarcRecord { block, ..record }))
+    }
+}
+impl Iterator for WarcReader {
+    type Item = Result<WarcRecord>;
+    fn next(&mut self) -> Option<Result<WarcRecord>> {
+        if self.broken { return None }
+        let record = self.read_record();
+        self.broken = record.is_err();
+        record.transpose()
+    }
+}
+
+///Runs every complete 200 response in a WARC back through `parse_page`, no network needed.
+///Gives back each page's url along with the page, filled in as far as the archive allows.
+pub fn replay<'a>(path: &Path, parse_opts: &'a ParseOptions) -> Result<impl Iterator<Item = Result<(CanonicalUrl, ParsedPage)>> + 'a> {
+    let records = WarcReader::open(path)?;
+    Ok(records.filter_map(move |record| match record {
+        Ok(record) => replay_record(&record, parse_opts).map(Ok),
+        Err(e) => Some(Err(e))
+    }))
+}
+
+fn replay_record(record: &WarcRecord, parse_opts: &ParseOptions) -> Option<(CanonicalUrl, ParsedPage)> {
+    if record.header("WARC-Type") != Some("response") || record.header("WARC-Truncated").is_some() { return None }
+    let url = CanonicalUrl::parse(record.header("WARC-Target-URI")?, &parse_opts.filter)?;
+    let split = record.block.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let (head, body) = (String::from_utf8_lossy(&record.block[..split]), &record.block[split + 4..]);
+    let mut lines = head.lines();
+    let status: u16 = lines.next()?.split_whitespace().nth(1)?.parse().ok()?;
+    if status != 200 { return None }
+    let headers: Vec<(&str, &str)> = lines.filter_map(|line| line.split_once(':')).map(|(name, val)| (name.trim(), val.trim())).collect();
+    let header = |wanted: &'static str| headers.iter().filter(move |(name, _)| name.eq_ignore_ascii_case(wanted)).map(|(_, val)| *val);
+    let content_type = header("content-type").next().map(String::from);
+    let page = decode_page(body, content_type.as_deref(), &url);
+    let mut parsed = parse_page(page, &url, parse_opts);
+    //Same as a live crawl - X-Robots-Tag counts along with the page's own meta tags
+    for value in header("x-robots-tag") { parsed.directives.add_header(value, ROBOTS_TOKEN); }
+    let fetched_at = record.header("WARC-Date").and_then(parse_warc_date).unwrap_or(0);
+    let data = PageData { status, final_url: url.to_string(), content_type, content_length: body.len() as u64, fetched_at, content_hash: Some(content_hash(body)), ..parsed.data };
+    Some((url, ParsedPage { data, ..parsed }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    fn exchange(url: &str, status: &str, body: &str) -> Exchange {
+        Exchange { url: url.to_string(), ip: Some("127.0.0.1".parse().unwrap()), fetched_at: 1_700_000_000, request: b"GET / HTTP/1.1\r\n\r\n".to_vec(),
+            response: format!("HTTP/1.1 {status}\r\ncontent-type: text/html; charset=utf-8\r\nx-robots-tag: nofollow\r\n\r\n").into_bytes(), body: body.as_bytes().to_vec(), truncated: false }
+    }
+    fn temp_base(name: &str) -> PathBuf { std::env::temp_dir().join(format!("web_crawler_test_{}_{name}.warc.gz", std::process::id())) }
+    fn file(base: &Path, index: usize) -> PathBuf { PathBuf::from(format!("{}-{index:05}.warc.gz", base.to_string_lossy().strip_suffix(".warc.gz").unwrap())) }
+    //Date Tests
+    #[test]
+    fn warc_dates() {
+        assert_eq!(warc_date(0), "1970-01-01T00:00:00Z");
+        assert_eq!(warc_date(1_709_296_200), "2024-03-01T12:30:00Z");
+        assert_eq!(warc_date(951_782_400), "2000-02-29T00:00:00Z");
+        for secs in [0, 951_782_400, 1_709_296_200, 4_102_444_799] { assert_eq!(parse_warc_date(&warc_date(secs)), Some(secs)); }
+        assert_eq!(parse_warc_date("yesterday"), None);
+    }
+    #[test]
+    fn record_ids_are_uuids() {
+        let id = record_id();
+        assert!(id.starts_with("<urn:uuid:") && id.ends_with('>'));
+        assert_eq!(id.len(), "<urn:uuid:>".len() + 36);
+        assert_eq!(&id[24..25], "4");
+        assert_ne!(id, record_id());
+    }
+    //Exchange Tests
+    #[test]
+    fn head_matches_stored_body() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "text/html".parse().unwrap());
+        headers.insert(TRANSFER_ENCODING, "chunked".parse().unwrap());
+        let head = String::from_utf8(response_head("HTTP/1.1 200 OK", &headers, Some(5))).unwrap();
+        assert_eq!(head, "HTTP/1.1 200 OK\r\ncontent-type: text/html\r\ncontent-length: 5\r\n\r\n");
+        //A body we gave up on partway keeps the length the server claimed
+        headers.remove(TRANSFER_ENCODING);
+        headers.insert(CONTENT_LENGTH, "99999".parse().unwrap());
+        let head = String::from_utf8(response_head("HTTP/1.1 200 OK", &headers, None)).unwrap();
+        assert_eq!(head, "HTTP/1.1 200 OK\r\ncontent-type: text/html\r\ncontent-length: 99999\r\n\r\n");
+    }
+    //Writer/Reader Tests
+    #[test]
+    fn write_then_replay() {
+        let base = temp_base("replay");
+        let writer = WarcWriter::spawn(&base, DEFAULT_WARC_MAX_SIZE).unwrap();
+        let sender = writer.sender();
+        sender.send(WarcMsg::Exchange(Box::new(exchange("https://gwango.lol/", "200 OK", "<title>Gwango</title><a href='/about'>About us</a>")))).unwrap();
+        sender.send(WarcMsg::Exchange(Box::new(exchange("https://gwango.lol/old", "301 Moved Permanently", "")))).unwrap();
+        sender.send(WarcMsg::Exchange(Box::new(Exchange { truncated: true, ..exchange("https://gwango.lol/big", "200 OK", "<title>Cut") })))
+            .unwrap();
+        let stats = writer.finish();
+        assert_eq!((stats.exchanges, stats.files, stats.failed.len()), (3, 1, 0));
+        //warcinfo, then a response and request for each exchange
+        let records: Vec<WarcRecord> = WarcReader::open(&file(&base, 0)).unwrap().map(Result::unwrap).collect();
+        let types: Vec<&str> = records.iter().map(|record| record.header("WARC-Type").unwrap()).collect();
+        assert_eq!(types, ["warcinfo", "response", "request", "response", "request", "response", "request"]);
+        assert_eq!(records[2].header("WARC-Concurrent-To"), records[1].header("WARC-Record-ID"));
+        assert_eq!(records[1].header("WARC-Date"), Some("2023-11-14T22:13:20Z"));
+        assert_eq!(records[1].header("WARC-IP-Address"), Some("127.0.0.1"));
+        assert!(records[1].block.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        //Only the complete 200 makes it back out
+        let pages: Vec<(CanonicalUrl, ParsedPage)> = replay(&file(&base, 0), &ParseOptions::default()).unwrap().map(Result::unwrap).collect();
+        assert_eq!(pages.len(), 1);
+        let (url, page) = &pages[0];
+        assert_eq!(url.as_str(), "https://gwango.lol/");
+        assert_eq!(page.data.title.as_deref(), Some("Gwango"));
+        assert_eq!(page.data.fetched_at, 1_700_000_000);
+        assert_eq!(page.data.content_type.as_deref(), Some("text/html; charset=utf-8"));
+        assert_eq!(page.links[0].url.as_str(), "https://gwango.lol/about");
+        assert!(page.directives.nofollow && !page.directives.noindex);
+        let _ = std::fs::remove_file(file(&base, 0));
+    }
+    #[test]
+    fn rotates_by_size() {
+        let base = temp_base("rotate");
+        let writer = WarcWriter::spawn(&base, 1).unwrap();
+        for page in 0..3 { writer.sender().send(WarcMsg::Exchange(Box::new(exchange(&format!("https://gwango.lol/{page}"), "200 OK", "hi")))).unwrap(); }
+        let stats = writer.finish();
+        //Every file is over the limit after one exchange, so each gets its own
+        assert_eq!((stats.exchanges, stats.files), (3, 3));
+        for index in 0..3 {
+            let records: Vec<WarcRecord> = WarcReader::open(&file(&base, index)).unwrap().map(Result::unwrap).collect();
+            assert_eq!(records.len(), 3);
+            assert_eq!(records[1].header("WARC-Target-URI"), Some(format!("https://gwango.lol/{index}").as_str()));
+            let _ = std::fs::remove_file(file(&base, index));
+        }
+    }
+    #[test]
+    fn reader_stops_at_garbage() {
+        let raw = "WARC/1.1\r\nWARC-Type: resource\r\nContent-Length: 2\r\n\r\nhi\r\n\r\nnot a warc record\r\n";
+        let mut reader = WarcReader::new(Box::new(Cursor::new(raw.as_bytes().to_vec())));
+        assert_eq!(reader.next().unwrap().unwrap().block, b"hi");
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+    #[test]
+    fn reader_catches_short_blocks() {
+        //Claims far more than is there - an error, not a huge allocation
+        let raw = "WARC/1.1\r\nWARC-Type: resource\r\nContent-Length: 99999999999\r\n\r\nhi";
+        let mut reader = WarcReader::new(Box::new(Cursor::new(raw.as_bytes().to_vec())));
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+}